@@ -0,0 +1,849 @@
+/*!
+Instrumented connections for `diesel-async`.
+
+With the `async` feature, [`InstrumentedAsyncPgConnection`] and
+[`InstrumentedAsyncMysqlConnection`] wrap the connections of `diesel-async`
+the way the connections of the backend modules wrap diesel's, recording the
+same fields on a span for each operation. Each span covers the operation from
+when its future is first polled until it completes, and for loads until the
+stream of rows has been read to the end or dropped.
+
+```no_run
+# async fn run() -> diesel::QueryResult<()> {
+use diesel_async::{AsyncConnection, RunQueryDsl};
+use diesel_tracing::async_connection::InstrumentedAsyncPgConnection;
+
+let mut conn = InstrumentedAsyncPgConnection::establish("postgres://localhost")
+    .await
+    .unwrap();
+diesel::sql_query("SELECT 1").execute(&mut conn).await?;
+# Ok(())
+# }
+```
+*/
+use crate::config::{StatementPolicy, TracingConfig};
+use crate::fields::SpanFields;
+use crate::guard;
+use crate::hooks;
+#[cfg(feature = "metrics")]
+use crate::meter;
+use crate::query::RowCount;
+use crate::sampling::{QuerySpan, SpanSampler};
+use crate::slow;
+use crate::summary;
+use diesel::connection::Instrumentation;
+use diesel::query_builder::{AsQuery, QueryFragment, QueryId};
+use diesel::result::{ConnectionError, ConnectionResult, Error, QueryResult};
+use diesel::sql_types::{BigInt, Nullable, Text};
+use diesel_async::scoped_futures::ScopedBoxFuture;
+use diesel_async::{
+    AnsiTransactionManager, AsyncConnection, SimpleAsyncConnection, TransactionManager,
+};
+use futures_core::Stream;
+use futures_util::future::BoxFuture;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::{debug, error, field, Instrument, Span};
+
+#[cfg(any(feature = "bb8", feature = "deadpool"))]
+pub mod pool;
+
+/// An instrumented `diesel_async::AsyncPgConnection`.
+#[cfg(feature = "postgres")]
+pub type InstrumentedAsyncPgConnection =
+    InstrumentedAsyncConnection<diesel_async::AsyncPgConnection>;
+
+/// An instrumented `diesel_async::AsyncMysqlConnection`.
+#[cfg(feature = "mysql")]
+pub type InstrumentedAsyncMysqlConnection =
+    InstrumentedAsyncConnection<diesel_async::AsyncMysqlConnection>;
+
+/// Information about the server a connection is established to, gathered at
+/// establish and recorded on the connection's spans.
+#[derive(QueryableByName, Clone, Debug, PartialEq)]
+pub struct AsyncConnectionInfo {
+    // db.name
+    #[diesel(sql_type = Nullable<Text>)]
+    name: Option<String>,
+    // db.version
+    #[diesel(sql_type = Text)]
+    version: String,
+    // net.peer.ip or net.peer.name, depending on the backend
+    #[diesel(sql_type = Nullable<Text>)]
+    host: Option<String>,
+    // net.peer.port
+    #[diesel(sql_type = Nullable<BigInt>)]
+    port: Option<i64>,
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// The connections of `diesel-async` that can be instrumented.
+///
+/// This is implemented for the connections of the backends enabled by
+/// feature flags and cannot be implemented outside of this crate.
+pub trait SupportedAsyncConnection:
+    AsyncConnection<TransactionManager = AnsiTransactionManager> + private::Sealed + 'static
+{
+    /// The value recorded as `db.system`.
+    #[doc(hidden)]
+    const SYSTEM: &'static str;
+
+    /// The field the server's host is recorded as.
+    #[doc(hidden)]
+    const HOST_FIELD: &'static str;
+
+    /// Queries the connection information from the server.
+    #[doc(hidden)]
+    fn query_info(&mut self) -> BoxFuture<'_, QueryResult<AsyncConnectionInfo>>;
+
+    /// Renders `query` as `diesel::debug_query` does.
+    #[doc(hidden)]
+    fn render<T: QueryFragment<Self::Backend>>(query: &T) -> Option<String>;
+}
+
+#[cfg(feature = "postgres")]
+impl private::Sealed for diesel_async::AsyncPgConnection {}
+
+#[cfg(feature = "postgres")]
+impl SupportedAsyncConnection for diesel_async::AsyncPgConnection {
+    const SYSTEM: &'static str = "postgresql";
+    const HOST_FIELD: &'static str = "net.peer.ip";
+
+    fn query_info(&mut self) -> BoxFuture<'_, QueryResult<AsyncConnectionInfo>> {
+        Box::pin(async move {
+            diesel_async::RunQueryDsl::get_result(
+                diesel::sql_query(
+                    "SELECT current_database() AS name, version() AS version, \
+                     host(inet_server_addr()) AS host, inet_server_port()::bigint AS port",
+                ),
+                self,
+            )
+            .await
+        })
+    }
+
+    fn render<T: QueryFragment<Self::Backend>>(query: &T) -> Option<String> {
+        guard::guarded("statement rendering", || {
+            diesel::debug_query::<diesel::pg::Pg, _>(query).to_string()
+        })
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl private::Sealed for diesel_async::AsyncMysqlConnection {}
+
+#[cfg(feature = "mysql")]
+impl SupportedAsyncConnection for diesel_async::AsyncMysqlConnection {
+    const SYSTEM: &'static str = "mysql";
+    const HOST_FIELD: &'static str = "net.peer.name";
+
+    fn query_info(&mut self) -> BoxFuture<'_, QueryResult<AsyncConnectionInfo>> {
+        Box::pin(async move {
+            diesel_async::RunQueryDsl::get_result(
+                diesel::sql_query(
+                    "SELECT DATABASE() AS name, VERSION() AS version, \
+                     @@hostname AS host, CAST(@@port AS SIGNED) AS port",
+                ),
+                self,
+            )
+            .await
+        })
+    }
+
+    fn render<T: QueryFragment<Self::Backend>>(query: &T) -> Option<String> {
+        guard::guarded("statement rendering", || {
+            diesel::debug_query::<diesel::mysql::Mysql, _>(query).to_string()
+        })
+    }
+}
+
+/// Creates the span for an operation on an established connection, with
+/// every optional field left empty until the operation has finished.
+macro_rules! connection_span {
+    ($level:expr, $name:literal, $system:expr) => {
+        span_at!(
+            $level,
+            $name,
+            db.name = field::Empty,
+            db.system = $system,
+            db.version = field::Empty,
+            otel.kind = "client",
+            net.peer.ip = field::Empty,
+            net.peer.name = field::Empty,
+            net.peer.port = field::Empty,
+            correlation_id = field::Empty,
+            db.pool.name = field::Empty,
+            db.response.rows = field::Empty,
+            db.result = field::Empty,
+            db.namespace = field::Empty,
+            server.address = field::Empty,
+            server.port = field::Empty,
+            db.statement = field::Empty,
+            db.query.text = field::Empty,
+            otel.status_code = field::Empty,
+            otel.status_description = field::Empty,
+        )
+    };
+}
+
+/// A `diesel-async` connection recording telemetry for its operations.
+pub struct InstrumentedAsyncConnection<C> {
+    inner: C,
+    info: AsyncConnectionInfo,
+    config: Arc<TracingConfig>,
+    span_sampler: SpanSampler,
+}
+
+/// Records `fields` on `span` once an operation has finished, logging them if
+/// configured to or if it failed.
+fn finish(
+    system: &'static str,
+    span: &Span,
+    fields: &mut SpanFields<'static>,
+    config: &TracingConfig,
+    started: Instant,
+    error: Option<&Error>,
+) {
+    let elapsed = started.elapsed();
+    summary::record(elapsed, error.is_some());
+    #[cfg(feature = "metrics")]
+    {
+        let database = fields.get("db.name").map(ToString::to_string);
+        meter::query(system, database.as_deref(), span, elapsed, error.is_some());
+    }
+    let _entered = span.enter();
+    slow::check(config, elapsed, fields.get("db.statement"));
+    fields.push_status(error);
+    fields.record(span, config);
+    fields.log(config, error);
+    hooks::end(config, system, fields, elapsed, error);
+}
+
+/// The future of an instrumented operation, running within the operation's
+/// span.
+pub struct Traced<F> {
+    system: &'static str,
+    future: Pin<Box<F>>,
+    span: QuerySpan<'static>,
+    fields: SpanFields<'static>,
+    config: Arc<TracingConfig>,
+    // set while the operation is running
+    started: Option<Instant>,
+}
+
+impl<F> Traced<F> {
+    fn new(
+        system: &'static str,
+        future: F,
+        span: QuerySpan<'static>,
+        fields: SpanFields<'static>,
+        config: Arc<TracingConfig>,
+    ) -> Self {
+        Traced {
+            system,
+            future: Box::pin(future),
+            span,
+            fields,
+            config,
+            started: None,
+        }
+    }
+}
+
+impl<F, T> Future for Traced<F>
+where
+    F: Future<Output = QueryResult<T>>,
+    T: RowCount,
+{
+    type Output = QueryResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let (span, config, system, fields) = (&this.span, &this.config, this.system, &this.fields);
+        let started = *this.started.get_or_insert_with(|| {
+            let _entered = span.enter();
+            hooks::start(config, system, fields);
+            Instant::now()
+        });
+        let result = match this.span.in_scope(|| this.future.as_mut().poll(cx)) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        this.started = None;
+        if let Some(rows) = result.as_ref().ok().and_then(RowCount::row_count) {
+            this.fields.push("db.response.rows", rows);
+        }
+        finish(
+            this.system,
+            this.span.outcome(result.is_err()),
+            &mut this.fields,
+            &this.config,
+            started,
+            result.as_ref().err(),
+        );
+        Poll::Ready(result)
+    }
+}
+
+impl<F> Drop for Traced<F> {
+    fn drop(&mut self) {
+        if let Some(started) = self.started {
+            let _entered = self.span.enter();
+            slow::check_dropped(&self.config, started.elapsed());
+        }
+    }
+}
+
+/// The future of an instrumented load, resolving to a stream of rows that
+/// records how many rows it returned once it has been read to the end or
+/// dropped.
+pub struct TracedLoad<F> {
+    system: &'static str,
+    future: Pin<Box<F>>,
+    span: QuerySpan<'static>,
+    fields: Option<SpanFields<'static>>,
+    config: Arc<TracingConfig>,
+    // set while the query is running
+    started: Option<Instant>,
+}
+
+impl<F, S> Future for TracedLoad<F>
+where
+    F: Future<Output = QueryResult<S>>,
+{
+    type Output = QueryResult<TracedStream<S>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let (span, config, system, fields) = (&this.span, &this.config, this.system, &this.fields);
+        let started = *this.started.get_or_insert_with(|| {
+            if let Some(fields) = fields {
+                let _entered = span.enter();
+                hooks::start(config, system, fields);
+            }
+            Instant::now()
+        });
+        let result = match this.span.in_scope(|| this.future.as_mut().poll(cx)) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        this.started = None;
+        let mut fields = this.fields.take().unwrap_or_default();
+        Poll::Ready(match result {
+            Ok(stream) => Ok(TracedStream {
+                system: this.system,
+                stream: Box::pin(stream),
+                span: std::mem::replace(&mut this.span, Span::none().into()),
+                fields: Some(fields),
+                config: Arc::clone(&this.config),
+                started,
+                rows: 0,
+            }),
+            Err(e) => {
+                finish(
+                    this.system,
+                    this.span.outcome(true),
+                    &mut fields,
+                    &this.config,
+                    started,
+                    Some(&e),
+                );
+                Err(e)
+            }
+        })
+    }
+}
+
+impl<F> Drop for TracedLoad<F> {
+    fn drop(&mut self) {
+        if let Some(started) = self.started {
+            let _entered = self.span.enter();
+            slow::check_dropped(&self.config, started.elapsed());
+        }
+    }
+}
+
+/// The rows of an instrumented load.
+pub struct TracedStream<S> {
+    system: &'static str,
+    stream: Pin<Box<S>>,
+    span: QuerySpan<'static>,
+    // taken once the outcome has been recorded
+    fields: Option<SpanFields<'static>>,
+    config: Arc<TracingConfig>,
+    started: Instant,
+    rows: usize,
+}
+
+impl<S> TracedStream<S> {
+    /// Records the outcome of a load whose rows were read without error,
+    /// whether to the end of the stream or until it was dropped.
+    fn complete(&mut self, mut fields: SpanFields<'static>) {
+        fields.push("db.response.rows", self.rows);
+        fields.push("db.result", if self.rows == 0 { "empty" } else { "rows" });
+        finish(
+            self.system,
+            self.span.outcome(false),
+            &mut fields,
+            &self.config,
+            self.started,
+            None,
+        );
+    }
+}
+
+impl<S, R> Stream for TracedStream<S>
+where
+    S: Stream<Item = QueryResult<R>>,
+{
+    type Item = QueryResult<R>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = match this.span.in_scope(|| this.stream.as_mut().poll_next(cx)) {
+            Poll::Ready(item) => item,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        match (&item, this.fields.take()) {
+            (Some(Ok(_)), fields) => {
+                this.rows += 1;
+                this.fields = fields;
+            }
+            (Some(Err(e)), Some(mut fields)) => {
+                finish(
+                    this.system,
+                    this.span.outcome(true),
+                    &mut fields,
+                    &this.config,
+                    this.started,
+                    Some(e),
+                );
+            }
+            (None, Some(fields)) => this.complete(fields),
+            (_, None) => {}
+        }
+        Poll::Ready(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+impl<S> Drop for TracedStream<S> {
+    fn drop(&mut self) {
+        // the query has finished once its rows are being read, and `first`
+        // or `get_result` drop the stream after reading a single row
+        if let Some(fields) = self.fields.take() {
+            self.complete(fields);
+        }
+    }
+}
+
+impl<C: SupportedAsyncConnection> InstrumentedAsyncConnection<C> {
+    /// The wrapped connection, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced.
+    #[must_use]
+    pub fn as_inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// The wrapped connection, mutably, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced, and transactions should
+    /// not be started or ended through it as the wrapper would not know of
+    /// them.
+    pub fn as_inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwraps the connection, which is no longer traced.
+    #[must_use]
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// Establishes a connection, recording telemetry as described by `config`.
+    ///
+    /// Only the field budget, the fields mirrored into logs, the correlation
+    /// id, the pool name, the span levels, the statement policy and the slow
+    /// query threshold apply to these connections.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection cannot be established or if the connection
+    /// information cannot be queried from the server.
+    pub async fn establish_with_config(
+        database_url: &str,
+        config: TracingConfig,
+    ) -> ConnectionResult<Self> {
+        let span = span_at!(
+            config.establish_span_level,
+            "establish_with_config",
+            db.name = field::Empty,
+            db.system = C::SYSTEM,
+            db.version = field::Empty,
+            otel.kind = "client",
+            net.peer.ip = field::Empty,
+            net.peer.name = field::Empty,
+            net.peer.port = field::Empty,
+            correlation_id = field::Empty,
+            db.pool.name = field::Empty,
+            db.namespace = field::Empty,
+            server.address = field::Empty,
+            server.port = field::Empty,
+        );
+        let result = Self::establish_recorded(database_url, config)
+            .instrument(span.clone())
+            .await;
+        if let Err(e) = &result {
+            span.in_scope(|| error!(error = %e));
+        }
+        result
+    }
+
+    /// Establishes a connection, recording what is known about it on the
+    /// current span.
+    async fn establish_recorded(
+        database_url: &str,
+        config: TracingConfig,
+    ) -> ConnectionResult<Self> {
+        debug!("establishing async connection");
+        let mut inner = C::establish(database_url).await?;
+
+        debug!("querying async connection information");
+        let info = inner
+            .query_info()
+            .await
+            .map_err(ConnectionError::CouldntSetupConfiguration)?;
+
+        let conn = InstrumentedAsyncConnection {
+            inner,
+            info,
+            config: Arc::new(config),
+            span_sampler: SpanSampler::default(),
+        };
+        conn.fields().record(&Span::current(), &conn.config);
+
+        Ok(conn)
+    }
+
+    /// The connection fields, owned so that they can outlive the borrow of
+    /// the connection by an operation's future.
+    fn fields(&self) -> SpanFields<'static> {
+        let mut fields = SpanFields::default();
+        if let Some(name) = &self.info.name {
+            fields.push("db.name", name.clone());
+        }
+        fields.push("db.version", self.info.version.clone());
+        if let Some(host) = &self.info.host {
+            fields.push(C::HOST_FIELD, host.clone());
+        }
+        if let Some(port) = self.info.port {
+            fields.push("net.peer.port", port);
+        }
+        if let Some(id) = self.config.correlation_id() {
+            fields.push("correlation_id", id);
+        }
+        if let Some(pool) = &self.config.pool_name {
+            fields.push("db.pool.name", pool.clone());
+        }
+        fields
+    }
+
+    /// The connection fields with the statement, rendered by `render` only
+    /// if the statement policy records statements.
+    fn statement_fields(&self, render: impl FnOnce() -> Option<String>) -> SpanFields<'static> {
+        let mut fields = self.fields();
+        if let Some(statement) = (self.config.statement_policy != StatementPolicy::Omit)
+            .then(render)
+            .flatten()
+            .and_then(|sql| self.config.statement_policy.apply(&sql))
+        {
+            fields.push("db.statement", statement);
+        }
+        fields
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: SupportedAsyncConnection> SimpleAsyncConnection for InstrumentedAsyncConnection<C> {
+    async fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        let level = self.config.query_span_level;
+        let span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "batch_execute", C::SYSTEM)
+        });
+        let fields = self.statement_fields(|| Some(query.to_owned()));
+        let config = Arc::clone(&self.config);
+        let future = span.in_scope(|| {
+            debug!("executing batch query");
+            self.inner.batch_execute(query)
+        });
+        Traced::new(C::SYSTEM, future, span, fields, config).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: SupportedAsyncConnection> AsyncConnection for InstrumentedAsyncConnection<C> {
+    type ExecuteFuture<'conn, 'query> = Traced<C::ExecuteFuture<'conn, 'query>>;
+    type LoadFuture<'conn, 'query> = TracedLoad<C::LoadFuture<'conn, 'query>>;
+    type Stream<'conn, 'query> = TracedStream<C::Stream<'conn, 'query>>;
+    type Row<'conn, 'query> = C::Row<'conn, 'query>;
+    type Backend = C::Backend;
+    type TransactionManager = AnsiTransactionManager;
+
+    async fn establish(database_url: &str) -> ConnectionResult<Self> {
+        Self::establish_with_config(database_url, TracingConfig::global()).await
+    }
+
+    async fn transaction<'a, R, E, F>(&mut self, callback: F) -> Result<R, E>
+    where
+        F: for<'r> FnOnce(&'r mut Self) -> ScopedBoxFuture<'a, 'r, Result<R, E>> + Send + 'a,
+        E: From<Error> + Send + 'a,
+        R: Send + 'a,
+    {
+        let span = connection_span!(self.config.query_span_level, "transaction", C::SYSTEM);
+        self.fields().record(&span, &self.config);
+        span.in_scope(|| debug!("running transaction"));
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let result = Self::TransactionManager::transaction(self, callback)
+            .instrument(span.clone())
+            .await;
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        #[cfg(feature = "metrics")]
+        meter::transaction(
+            C::SYSTEM,
+            self.info.name.as_deref(),
+            started.elapsed(),
+            if result.is_err() {
+                "rollback"
+            } else {
+                "commit"
+            },
+        );
+        result
+    }
+
+    fn load<'conn, 'query, T>(&'conn mut self, source: T) -> Self::LoadFuture<'conn, 'query>
+    where
+        T: AsQuery + 'query,
+        T::Query: QueryFragment<Self::Backend> + QueryId + 'query,
+    {
+        let level = self.config.query_span_level;
+        let span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "load", C::SYSTEM)
+        });
+        let query = source.as_query();
+        let fields = self.statement_fields(|| C::render(&query));
+        let config = Arc::clone(&self.config);
+        let future = span.in_scope(|| {
+            debug!("loading rows");
+            self.inner.load(query)
+        });
+        TracedLoad {
+            system: C::SYSTEM,
+            future: Box::pin(future),
+            span,
+            fields: Some(fields),
+            config,
+            started: None,
+        }
+    }
+
+    fn execute_returning_count<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> Self::ExecuteFuture<'conn, 'query>
+    where
+        T: QueryFragment<Self::Backend> + QueryId + 'query,
+    {
+        let level = self.config.query_span_level;
+        let span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "execute_returning_count", C::SYSTEM)
+        });
+        let fields = self.statement_fields(|| C::render(&source));
+        let config = Arc::clone(&self.config);
+        let future = span.in_scope(|| {
+            debug!("executing returning count");
+            self.inner.execute_returning_count(source)
+        });
+        Traced::new(C::SYSTEM, future, span, fields, config)
+    }
+
+    fn transaction_state(
+        &mut self,
+    ) -> &mut <Self::TransactionManager as TransactionManager<Self>>::TransactionStateData {
+        self.inner.transaction_state()
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        self.inner.instrumentation()
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.inner.set_instrumentation(instrumentation);
+    }
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+    use diesel::sql_types::Integer;
+    use diesel::IntoSql;
+    use diesel_async::scoped_futures::ScopedFutureExt;
+    use diesel_async::RunQueryDsl;
+    use futures_util::FutureExt;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_async_operations_are_traced() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let mut conn = InstrumentedAsyncPgConnection::establish(&url)
+            .await
+            .expect("failed to establish connection");
+
+        let rows: Vec<i32> = diesel::select(1.into_sql::<Integer>())
+            .load(&mut conn)
+            .await
+            .expect("failed to load");
+        assert_eq!(rows, [1]);
+        let span = capture.span("load");
+        assert_eq!(span.field("db.system"), Some("postgresql"));
+        assert_eq!(span.field("db.response.rows"), Some("1"));
+        assert!(span.field("db.name").is_some());
+
+        conn.transaction::<_, Error, _>(|conn| {
+            async move { conn.batch_execute("SELECT 1").await }.scope_boxed()
+        })
+        .await
+        .expect("failed to run transaction");
+        let span = capture.span("transaction");
+        assert_eq!(span.field("db.system"), Some("postgresql"));
+
+        let error = diesel::sql_query("SELECT * FROM no_such_table")
+            .execute(&mut conn)
+            .await;
+        assert!(error.is_err());
+        capture.event("relation \"no_such_table\" does not exist");
+    }
+
+    #[tokio::test]
+    async fn test_slow_async_queries_are_logged() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let mut conn = InstrumentedAsyncPgConnection::establish_with_config(
+            &url,
+            TracingConfig::default()
+                .with_slow_query_threshold(Duration::from_millis(50))
+                .with_statement_policy(StatementPolicy::Full),
+        )
+        .await
+        .expect("failed to establish connection");
+        conn.batch_execute("SELECT 1")
+            .await
+            .expect("failed to execute");
+        assert!(capture.events_named("slow query").is_empty());
+
+        conn.batch_execute("SELECT pg_sleep(0.1)")
+            .await
+            .expect("failed to execute");
+        let event = capture.event("slow query");
+        assert_eq!(event.field("db.statement"), Some("SELECT pg_sleep(0.1)"));
+        assert_eq!(event.field("db.slow_query.threshold_ms"), Some("50"));
+        assert_eq!(event.field("db.cancelled"), None);
+        assert_eq!(event.parent, Some("batch_execute"));
+    }
+
+    #[tokio::test]
+    async fn test_slow_async_queries_are_logged_when_dropped() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let mut conn = InstrumentedAsyncPgConnection::establish_with_config(
+            &url,
+            TracingConfig::default().with_slow_query_threshold(Duration::from_millis(50)),
+        )
+        .await
+        .expect("failed to establish connection");
+
+        let mut query = Box::pin(diesel::sql_query("SELECT pg_sleep(1)").execute(&mut conn));
+        assert!(query.as_mut().now_or_never().is_none());
+        // the query is still running on the server when its future is dropped
+        std::thread::sleep(Duration::from_millis(100));
+        drop(query);
+
+        let event = capture.event("slow query");
+        assert_eq!(event.field("db.cancelled"), Some("true"));
+        assert_eq!(event.parent, Some("execute_returning_count"));
+    }
+
+    #[tokio::test]
+    async fn test_single_row_reads_record_their_outcome() {
+        use std::sync::Mutex;
+
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let ended = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::clone(&ended);
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let mut conn = InstrumentedAsyncPgConnection::establish_with_config(
+            &url,
+            TracingConfig::default()
+                .with_slow_query_threshold(Duration::from_millis(50))
+                .with_statement_policy(StatementPolicy::Full)
+                .with_query_end_hook(move |query| {
+                    calls
+                        .lock()
+                        .unwrap()
+                        .push((query.rows, query.error.is_some()));
+                }),
+        )
+        .await
+        .expect("failed to establish connection");
+        ended.lock().unwrap().clear();
+
+        // `get_result` drops the stream once it has read a row
+        let (one, _): (i32, bool) = diesel::select((
+            1.into_sql::<Integer>(),
+            diesel::dsl::sql::<diesel::sql_types::Bool>("(SELECT true FROM pg_sleep(0.1))"),
+        ))
+        .get_result(&mut conn)
+        .await
+        .expect("failed to get result");
+        assert_eq!(one, 1);
+
+        let span = capture.span("load");
+        assert_eq!(span.field("db.response.rows"), Some("1"));
+        assert_eq!(span.field("db.result"), Some("rows"));
+        assert_eq!(span.field("otel.status_code"), Some("OK"));
+        assert!(!span.failed);
+        assert_eq!(*ended.lock().unwrap(), [(Some(1), false)]);
+
+        // having finished, the query is slow rather than cancelled
+        let event = capture.event("slow query");
+        assert!(event.field("db.statement").is_some());
+        assert_eq!(event.field("db.cancelled"), None);
+        assert_eq!(event.parent, Some("load"));
+    }
+}
@@ -0,0 +1,130 @@
+/*!
+Pools of instrumented async connections, with `bb8` or `deadpool`.
+
+With the `bb8` or `deadpool` feature, instrumented async connections can be
+pooled through `diesel-async`'s pooled connection managers, built with
+[`InstrumentedAsyncConnection::pool_manager`] so that every connection of the
+pool records telemetry as configured. Naming the pool with
+`TracingConfig::with_pool_name` records `db.pool.name` on the spans of its
+connections, and [`checkout`] traces checkouts from the pool.
+
+```no_run
+# #[cfg(all(feature = "bb8", feature = "postgres"))]
+# async fn run() {
+use diesel_async::pooled_connection::bb8::Pool;
+use diesel_tracing::async_connection::pool::checkout;
+use diesel_tracing::async_connection::InstrumentedAsyncPgConnection;
+use diesel_tracing::TracingConfig;
+
+let manager = InstrumentedAsyncPgConnection::pool_manager(
+    "postgres://localhost",
+    TracingConfig::default().with_pool_name("primary"),
+);
+let pool = Pool::builder().build(manager).await.unwrap();
+let conn = checkout("primary", pool.get()).await.unwrap();
+# }
+```
+*/
+use super::{InstrumentedAsyncConnection, SupportedAsyncConnection};
+use crate::config::TracingConfig;
+use diesel_async::pooled_connection::{
+    AsyncDieselConnectionManager, ManagerConfig, PoolableConnection,
+};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Instant;
+use tracing::{debug_span, error, field, Instrument};
+
+impl<C: SupportedAsyncConnection> PoolableConnection for InstrumentedAsyncConnection<C> {}
+
+impl<C: SupportedAsyncConnection> InstrumentedAsyncConnection<C> {
+    /// A connection manager for pools of connections to `database_url`,
+    /// recording telemetry as described by `config`.
+    #[must_use]
+    pub fn pool_manager(
+        database_url: impl Into<String>,
+        config: TracingConfig,
+    ) -> AsyncDieselConnectionManager<Self> {
+        let manager_config = ManagerConfig {
+            custom_setup: Box::new(move |url| {
+                Box::pin(Self::establish_with_config(url, config.clone()))
+            }),
+            ..ManagerConfig::default()
+        };
+        AsyncDieselConnectionManager::new_with_config(database_url, manager_config)
+    }
+}
+
+/// Awaits `checkout`, checking out a connection from the pool named `pool`
+/// such as with `pool.get()`, in a `checkout` span recording how long it
+/// waited for the connection as `db.pool.wait_ms`.
+///
+/// # Errors
+///
+/// Fails with the error of `checkout`, which is also logged.
+pub async fn checkout<F, T, E>(pool: &str, checkout: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let span = debug_span!(
+        "checkout",
+        db.pool.name = pool,
+        db.pool.wait_ms = field::Empty,
+        otel.status_code = field::Empty,
+    );
+    let start = Instant::now();
+    let result = checkout.instrument(span.clone()).await;
+
+    span.record(
+        "db.pool.wait_ms",
+        u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX),
+    );
+    if let Err(e) = &result {
+        span.record("otel.status_code", "ERROR");
+        span.in_scope(|| error!(error = %e, "failed to check out pooled connection"));
+    }
+    result
+}
+
+#[cfg(all(test, feature = "bb8", feature = "postgres"))]
+mod tests {
+    use super::*;
+    use crate::async_connection::InstrumentedAsyncPgConnection;
+    use crate::test::Capture;
+    use diesel_async::pooled_connection::bb8::Pool;
+    use diesel_async::SimpleAsyncConnection;
+
+    #[tokio::test]
+    async fn test_pooled_connections_are_traced() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let manager = InstrumentedAsyncPgConnection::pool_manager(
+            url,
+            TracingConfig::default().with_pool_name("primary"),
+        );
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(manager)
+            .await
+            .expect("failed to build pool");
+
+        let mut conn = checkout("primary", pool.get())
+            .await
+            .expect("failed to check out connection");
+        conn.batch_execute("SELECT 1")
+            .await
+            .expect("failed to run pooled query");
+
+        let span = capture.span("checkout");
+        assert_eq!(span.field("db.pool.name"), Some("primary"));
+        assert!(span.field("db.pool.wait_ms").is_some());
+        assert_eq!(
+            capture.span("batch_execute").field("db.pool.name"),
+            Some("primary")
+        );
+    }
+}
@@ -0,0 +1,192 @@
+/*!
+Spans declaring more fields than tracing's macros allow.
+
+Tracing's span macros pass a value for every field they declare, and a span
+can be created with at most 32 values, which the spans of postgres
+operations have outgrown. The fields of a span only have to be named in its
+callsite's metadata to be recorded later on though, so `wide_span!`
+declares its own callsites, naming every field but passing values only for
+those known when the span is created.
+
+The target of a callsite's metadata is fixed as well, so spans given a target
+of their own are created at a copy of their callsite with that target, made
+and registered the first time a span of the callsite takes the target.
+*/
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{OnceLock, PoisonError, RwLock};
+use tracing::callsite::{Callsite, DefaultCallsite, Identifier};
+use tracing::field::FieldSet;
+use tracing::level_filters::{LevelFilter, STATIC_MAX_LEVEL};
+use tracing::metadata::Kind;
+use tracing::subscriber::Interest;
+use tracing::Metadata;
+
+/// A callsite of `wide_span!` with the target given to its spans.
+pub(crate) struct TargetedCallsite {
+    meta: OnceLock<Metadata<'static>>,
+    interest: AtomicU8,
+}
+
+const NEVER: u8 = 0;
+const SOMETIMES: u8 = 1;
+const ALWAYS: u8 = 2;
+
+impl TargetedCallsite {
+    pub(crate) fn interest(&self) -> Interest {
+        match self.interest.load(Ordering::Relaxed) {
+            NEVER => Interest::never(),
+            ALWAYS => Interest::always(),
+            _ => Interest::sometimes(),
+        }
+    }
+}
+
+impl Callsite for TargetedCallsite {
+    fn set_interest(&self, interest: Interest) {
+        let interest = if interest.is_never() {
+            NEVER
+        } else if interest.is_always() {
+            ALWAYS
+        } else {
+            SOMETIMES
+        };
+        self.interest.store(interest, Ordering::Relaxed);
+    }
+
+    fn metadata(&self) -> &Metadata<'_> {
+        self.meta.get().expect("the metadata is set on creation")
+    }
+}
+
+/// The copy of `base`, declaring `fields`, whose spans have the target
+/// `target`.
+///
+/// Each copy is leaked, as callsites are registered for the lifetime of the
+/// program, but only one is made for each callsite and target.
+pub(crate) fn targeted(
+    base: &'static DefaultCallsite,
+    fields: &'static [&'static str],
+    target: &'static str,
+) -> &'static TargetedCallsite {
+    static CALLSITES: OnceLock<RwLock<HashMap<(usize, &str), &'static TargetedCallsite>>> =
+        OnceLock::new();
+    let callsites = CALLSITES.get_or_init(RwLock::default);
+    let key = (base as *const DefaultCallsite as usize, target);
+    if let Some(callsite) = callsites
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&key)
+        .copied()
+    {
+        return callsite;
+    }
+
+    let mut callsites = callsites.write().unwrap_or_else(PoisonError::into_inner);
+    *callsites.entry(key).or_insert_with(|| {
+        let callsite: &'static TargetedCallsite = Box::leak(Box::new(TargetedCallsite {
+            meta: OnceLock::new(),
+            interest: AtomicU8::new(SOMETIMES),
+        }));
+        let meta = base.metadata();
+        // `Metadata` has no accessor for its kind, but every callsite of
+        // `wide_span!` is a span's
+        let _ = callsite.meta.set(Metadata::new(
+            meta.name(),
+            target,
+            *meta.level(),
+            meta.file(),
+            meta.line(),
+            meta.module_path(),
+            FieldSet::new(fields, Identifier(callsite)),
+            Kind::SPAN,
+        ));
+        tracing::callsite::register(callsite);
+        callsite
+    })
+}
+
+/// Whether a span of the callsite of `meta`, with `interest`, would be
+/// recorded by the current subscriber, checked as tracing's macros check it,
+/// which it never is with the `noop` feature.
+pub(crate) fn enabled(meta: &'static Metadata<'static>, interest: Interest) -> bool {
+    if cfg!(feature = "noop") {
+        return false;
+    }
+    let level = *meta.level();
+    if level > STATIC_MAX_LEVEL || level > LevelFilter::current() {
+        return false;
+    }
+    !interest.is_never()
+        && (interest.is_always()
+            || tracing::dispatcher::get_default(|current| current.enabled(meta)))
+}
+
+/// Creates a span at the `SpanSite` `$site` with the fields in braces set and
+/// the fields in brackets declared but left empty.
+///
+/// Like `span_at!`, the callsite is declared once for each level.
+macro_rules! wide_span {
+    (@meta $name:literal, $level:expr, $fields:ident, $callsite:ident) => {
+        tracing::Metadata::new(
+            $name,
+            module_path!(),
+            $level,
+            Some(file!()),
+            Some(line!()),
+            Some(module_path!()),
+            tracing::field::FieldSet::new($fields, tracing::callsite::Identifier(&$callsite)),
+            tracing::metadata::Kind::SPAN,
+        )
+    };
+    (
+        $site:expr,
+        $name:literal,
+        { $($($key:ident).+ = $value:expr),* $(,)? },
+        [ $($($field:ident).+),* $(,)? ]
+    ) => {{
+        use tracing::callsite::Callsite as _;
+
+        const FIELDS: &[&str] = &[$(stringify!($($key).+),)* $(stringify!($($field).+),)*];
+        static TRACE: tracing::callsite::DefaultCallsite =
+            tracing::callsite::DefaultCallsite::new(&TRACE_META);
+        static TRACE_META: tracing::Metadata<'static> =
+            wide_span!(@meta $name, tracing::Level::TRACE, FIELDS, TRACE);
+        static DEBUG: tracing::callsite::DefaultCallsite =
+            tracing::callsite::DefaultCallsite::new(&DEBUG_META);
+        static DEBUG_META: tracing::Metadata<'static> =
+            wide_span!(@meta $name, tracing::Level::DEBUG, FIELDS, DEBUG);
+        static INFO: tracing::callsite::DefaultCallsite =
+            tracing::callsite::DefaultCallsite::new(&INFO_META);
+        static INFO_META: tracing::Metadata<'static> =
+            wide_span!(@meta $name, tracing::Level::INFO, FIELDS, INFO);
+
+        let site: $crate::config::SpanSite = $site;
+        let callsite: &'static tracing::callsite::DefaultCallsite = match site.level {
+            $crate::config::SpanLevel::Trace => &TRACE,
+            $crate::config::SpanLevel::Debug => &DEBUG,
+            $crate::config::SpanLevel::Info => &INFO,
+        };
+        let (meta, interest) = match site.target {
+            Some(target) => {
+                let targeted = $crate::callsite::targeted(callsite, FIELDS, target);
+                (targeted.metadata(), targeted.interest())
+            }
+            None => (callsite.metadata(), callsite.interest()),
+        };
+        if $crate::callsite::enabled(meta, interest) {
+            let fields = meta.fields();
+            tracing::Span::new(
+                meta,
+                &fields.value_set(&[$((
+                    &fields
+                        .field(stringify!($($key).+))
+                        .expect("the field is declared by the callsite"),
+                    Some(&$value as &dyn tracing::Value),
+                ),)*]),
+            )
+        } else {
+            tracing::Span::new_disabled(meta)
+        }
+    }};
+}
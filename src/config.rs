@@ -0,0 +1,970 @@
+use crate::fingerprint;
+use crate::guard;
+use crate::hooks::{QueryEnd, QueryStart};
+use diesel::connection::CacheSize;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::warn;
+
+/// The configuration of connections established without one, once set with
+/// `TracingConfig::set_global`.
+static GLOBAL: OnceLock<TracingConfig> = OnceLock::new();
+
+/// A function supplied by the application, shared between the clones of a
+/// configuration.
+#[derive(Clone)]
+pub(crate) struct Hook<F: ?Sized>(pub(crate) Arc<F>);
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// Configuration for the telemetry recorded by instrumented connections.
+///
+/// The default configuration records the same fields as a connection
+/// established through `diesel::Connection::establish`.
+#[derive(Clone, Debug, Default)]
+pub struct TracingConfig {
+    pub(crate) field_budget: Option<usize>,
+    pub(crate) proxy: Option<Proxy>,
+    pub(crate) transaction_search_path: bool,
+    pub(crate) log_fields: HashSet<String>,
+    pub(crate) page_full: bool,
+    pub(crate) max_connections: bool,
+    pub(crate) tls_info: bool,
+    pub(crate) application_name: bool,
+    pub(crate) application_name_from: Option<Hook<dyn Fn() -> Option<String> + Send + Sync>>,
+    pub(crate) wait_sampling: Option<Duration>,
+    pub(crate) establish_event: bool,
+    pub(crate) establish_links: bool,
+    pub(crate) role_connection_limit: bool,
+    pub(crate) query_fingerprint: bool,
+    pub(crate) bind_check: bool,
+    pub(crate) build_info: bool,
+    pub(crate) query_complexity: bool,
+    pub(crate) statement_cache_size: Option<CacheSize>,
+    pub(crate) correlation_id: Option<Hook<dyn Fn() -> Option<String> + Send + Sync>>,
+    pub(crate) table_stats: bool,
+    pub(crate) max_row_bytes: Option<usize>,
+    pub(crate) server_duration: Option<Duration>,
+    pub(crate) statement_policy: StatementPolicy,
+    pub(crate) info_query: InfoQuery,
+    pub(crate) info_refresh: bool,
+    pub(crate) semantic_conventions: SemanticConventions,
+    pub(crate) slow_query: Option<Duration>,
+    pub(crate) slow_query_explain: Option<Duration>,
+    pub(crate) partition_key: Option<Hook<dyn Fn(&str) -> Option<String> + Send + Sync>>,
+    pub(crate) sql_commenter: Option<SqlCommenter>,
+    pub(crate) pool_name: Option<String>,
+    pub(crate) query_span_level: SpanLevel,
+    pub(crate) query_sampling: Option<u32>,
+    pub(crate) establish_span_level: SpanLevel,
+    pub(crate) span_target: Option<&'static str>,
+    pub(crate) db_system: Option<&'static str>,
+    pub(crate) query_start_hook: Option<Hook<dyn Fn(&QueryStart<'_>) + Send + Sync>>,
+    pub(crate) query_end_hook: Option<Hook<dyn Fn(&QueryEnd<'_>) + Send + Sync>>,
+    pub(crate) batch_statements: bool,
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) attributes: Vec<opentelemetry::KeyValue>,
+}
+
+/// The tags of the SQLCommenter comment appended to statements.
+#[derive(Clone, Debug)]
+pub(crate) struct SqlCommenter {
+    pub(crate) application: String,
+    pub(crate) traceparent: Hook<dyn Fn() -> Option<String> + Send + Sync>,
+}
+
+/// Connection poolers that can sit between the client and the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Proxy {
+    /// `PgBouncer`, in any of its pooling modes.
+    PgBouncer,
+}
+
+impl Proxy {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Proxy::PgBouncer => "pgbouncer",
+        }
+    }
+}
+
+/// How much of a statement is recorded as `db.statement`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StatementPolicy {
+    /// Statements are not recorded.
+    #[default]
+    Omit,
+    /// Statements are recorded without their literals and binds, normalized
+    /// as `pg_stat_statements` displays them, as in
+    /// `select * from users where id = $1 and name = $2`.
+    Sanitized,
+    /// Statements are recorded as they are, typed queries rendered with
+    /// their binds as in `-- binds: [42]`.
+    Full,
+}
+
+/// The version of the `OpenTelemetry` semantic conventions for databases
+/// that the fields of spans are named after.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SemanticConventions {
+    /// The names of the conventions before they were stabilised, such as
+    /// `db.name`, `net.peer.ip` and `db.statement`.
+    #[default]
+    Legacy,
+    /// The names of the stable conventions, such as `db.namespace`,
+    /// `server.address` and `db.query.text`, for backends enforcing them.
+    Stable,
+    /// Both names, while dashboards and alerts move from one to the other.
+    Both,
+}
+
+impl SemanticConventions {
+    /// The names `name` is recorded under, which are the same for fields the
+    /// conventions did not rename.
+    pub(crate) fn names(self, name: &'static str) -> (Option<&'static str>, Option<&'static str>) {
+        let stable = match name {
+            "db.name" => "db.namespace",
+            "net.peer.ip" | "net.peer.name" => "server.address",
+            "net.peer.port" => "server.port",
+            "net.transport" => "network.transport",
+            "net.sock.family" => "network.type",
+            "db.statement" => "db.query.text",
+            "db.operation" => "db.operation.name",
+            "db.sql.table" => "db.collection.name",
+            _ => return (Some(name), None),
+        };
+        match self {
+            SemanticConventions::Legacy => (Some(name), None),
+            SemanticConventions::Stable => (None, Some(stable)),
+            SemanticConventions::Both => (Some(name), Some(stable)),
+        }
+    }
+
+    /// The value `value` of the field `name` is recorded as under its stable
+    /// name, which differs for the fields whose values were renamed too.
+    pub(crate) fn stable_value<'a>(name: &str, value: &'a str) -> &'a str {
+        match (name, value) {
+            ("net.transport", "ip_tcp") => "tcp",
+            ("net.sock.family", "inet") => "ipv4",
+            ("net.sock.family", "inet6") => "ipv6",
+            _ => value,
+        }
+    }
+}
+
+/// When the information about the server recorded on spans, such as the
+/// database name and the server's address, is queried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InfoQuery {
+    /// While establishing the connection, failing to establish it if the
+    /// query fails.
+    #[default]
+    Establish,
+    /// Before the first operation run on the connection, leaving the
+    /// information out of spans if the query fails.
+    Deferred,
+    /// Never, leaving the information out of spans.
+    Skip,
+}
+
+/// The level spans are created at, which subscribers filter them by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanLevel {
+    /// `TRACE`, for spans only wanted while debugging.
+    Trace,
+    /// `DEBUG`.
+    #[default]
+    Debug,
+    /// `INFO`, for spans wanted whenever the application is traced.
+    Info,
+}
+
+/// The level and target of a span, overriding the target of its callsite if
+/// it has one.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SpanSite {
+    pub(crate) level: SpanLevel,
+    pub(crate) target: Option<&'static str>,
+}
+
+/// Creates a span at the `SpanLevel` `$level`.
+///
+/// Tracing fixes the level of each callsite, so the span is declared once
+/// for each level.
+macro_rules! span_at {
+    ($level:expr, $($span:tt)+) => {
+        match $level {
+            $crate::config::SpanLevel::Trace => tracing::trace_span!($($span)+),
+            $crate::config::SpanLevel::Debug => tracing::debug_span!($($span)+),
+            $crate::config::SpanLevel::Info => tracing::info_span!($($span)+),
+        }
+    };
+}
+
+impl StatementPolicy {
+    /// The statement to record for `sql`, if any.
+    pub(crate) fn apply(self, sql: &str) -> Option<String> {
+        match self {
+            StatementPolicy::Omit => None,
+            StatementPolicy::Sanitized => Some(fingerprint::normalize(sql)),
+            StatementPolicy::Full => Some(sql.to_owned()),
+        }
+    }
+}
+
+impl TracingConfig {
+    /// The correlation id of the current request, if there is a reader for it
+    /// and it has one.
+    pub(crate) fn correlation_id(&self) -> Option<String> {
+        let Hook(read) = self.correlation_id.as_ref()?;
+        guard::guarded("correlation id", || read()).flatten()
+    }
+
+    /// The `application_name` to set for the current request, if there is a
+    /// reader for it and it has one.
+    pub(crate) fn application_name_from(&self) -> Option<String> {
+        let Hook(read) = self.application_name_from.as_ref()?;
+        guard::guarded("application name", || read()).flatten()
+    }
+
+    /// Sets the static attributes on `span`.
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) fn record_attributes(&self, span: &tracing::Span) {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        for attribute in &self.attributes {
+            span.set_attribute(attribute.key.clone(), attribute.value.clone());
+        }
+    }
+
+    /// Whether the connection information is queried again as it may go
+    /// stale, which skipping the query rules out.
+    pub(crate) fn refreshes_info(&self) -> bool {
+        self.info_refresh && self.info_query != InfoQuery::Skip
+    }
+
+    /// Whether any of the enabled options need statements to be rendered.
+    pub(crate) fn renders_statements(&self) -> bool {
+        self.statement_policy != StatementPolicy::Omit
+            || self.page_full
+            || self.query_fingerprint
+            || self.bind_check
+            || self.query_complexity
+            || self.table_stats
+            || self.partition_key.is_some()
+            || self.slow_query_explain.is_some()
+    }
+
+    /// Where the spans of operations on established connections are created.
+    pub(crate) fn query_site(&self) -> SpanSite {
+        SpanSite {
+            level: self.query_span_level,
+            target: self.span_target,
+        }
+    }
+
+    /// Where the spans of establishing connections are created.
+    pub(crate) fn establish_site(&self) -> SpanSite {
+        SpanSite {
+            level: self.establish_span_level,
+            target: self.span_target,
+        }
+    }
+
+    /// Limits the number of optional fields recorded on each span.
+    ///
+    /// Some collectors cap the number of attributes on a span and silently
+    /// drop whatever is over the limit. With a budget set the optional fields
+    /// are recorded in priority order, errors first and connection details
+    /// after, and everything past the budget is dropped so that which fields
+    /// survive is predictable. `db.system` and `otel.kind` are always recorded
+    /// and do not count towards the budget.
+    #[must_use]
+    pub fn with_field_budget(mut self, budget: usize) -> Self {
+        self.field_budget = Some(budget);
+        self
+    }
+
+    /// Declares that connections go through a connection pooler.
+    ///
+    /// The proxy is recorded as `db.proxy` and the connection information
+    /// gathered at establish is adapted to it: the server address is not
+    /// queried as it would describe the pooler's side of the connection, and
+    /// the query avoids server-side prepared statements, which do not survive
+    /// transaction pooling.
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Records the effective `search_path` as `db.search_path` on
+    /// transaction spans.
+    ///
+    /// The setting is read when the transaction starts and again before it
+    /// commits, so a `search_path` set locally within the transaction is the
+    /// one recorded. This is useful where the schema identifies a tenant.
+    #[must_use]
+    pub fn with_transaction_search_path(mut self, record: bool) -> Self {
+        self.transaction_search_path = record;
+        self
+    }
+
+    /// Mirrors the named fields into a log event for each operation.
+    ///
+    /// By default fields are only recorded on spans. Fields named here are
+    /// also added to an event emitted when an operation completes, at `DEBUG`
+    /// or at `ERROR` when it failed, so that log based alerting can see them
+    /// without the rest of the span. Errors are logged whether or not any
+    /// fields are mirrored.
+    #[must_use]
+    pub fn with_log_fields<I>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.log_fields = fields.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Records whether reads with a `LIMIT` returned a full page as
+    /// `db.result.page_full`.
+    ///
+    /// This tells the last page of a paginated listing apart from one with
+    /// more pages after it. The limit is parsed from the rendered statement,
+    /// which costs formatting each query, so this is off by default.
+    /// Statements without a limit are not recorded.
+    #[must_use]
+    pub fn with_page_full(mut self, record: bool) -> Self {
+        self.page_full = record;
+        self
+    }
+
+    /// Records the server's `max_connections` setting on the establish span
+    /// as `db.postgresql.max_connections`.
+    ///
+    /// Comparing this with the size of connection pools across a deployment
+    /// helps with capacity planning. It is gathered by the same query as the
+    /// rest of the connection information.
+    #[must_use]
+    pub fn with_max_connections(mut self, record: bool) -> Self {
+        self.max_connections = record;
+        self
+    }
+
+    /// Records whether postgres connections are encrypted on every span, as
+    /// `db.connection.encrypted`, with the version of TLS and the cipher of
+    /// encrypted connections as `tls.protocol.version` and `tls.cipher`.
+    ///
+    /// These are read from `pg_stat_ssl` by the same query as the rest of the
+    /// connection information. Behind a pooler they would describe the
+    /// pooler's connection to the server, so they are left out.
+    #[must_use]
+    pub fn with_tls_info(mut self, record: bool) -> Self {
+        self.tls_info = record;
+        self
+    }
+
+    /// Records the `application_name` the connection reports to the server
+    /// as `db.client.application_name`, alongside the `db.user` recorded on
+    /// every span.
+    ///
+    /// This ties the sessions seen in `pg_stat_activity` and the server's
+    /// logs to the services that opened them. It is gathered by the same
+    /// query as the rest of the connection information.
+    #[must_use]
+    pub fn with_application_name(mut self, record: bool) -> Self {
+        self.application_name = record;
+        self
+    }
+
+    /// Sets the `application_name` of postgres connections to the name
+    /// returned by `read`, when the connection is established and again for
+    /// the length of each transaction, so that a session or hung query seen
+    /// in `pg_stat_activity` can be traced back to the request running it.
+    ///
+    /// `read` should return a name including, for example, the service name
+    /// and the id of the current trace, or `None` to leave the name as it
+    /// is. The name set for a transaction is set with `SET LOCAL`, reverting
+    /// to the one set at establish once the transaction ends, which costs a
+    /// round trip at the start of each transaction. The server truncates
+    /// names to 63 bytes.
+    #[must_use]
+    pub fn with_application_name_from<F>(mut self, read: F) -> Self
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.application_name_from = Some(Hook(Arc::new(read)));
+        self
+    }
+
+    /// Samples what the server is waiting on while each query runs, recording
+    /// a rough breakdown as `db.wait.io_ms` and `db.wait.lock_ms`.
+    ///
+    /// This is a diagnostic mode and is expensive: every connection opens a
+    /// second connection to poll `pg_stat_activity` every `interval` from a
+    /// separate thread for as long as each query runs. Queries shorter than
+    /// the interval are not sampled at all.
+    #[must_use]
+    pub fn with_wait_sampling(mut self, interval: Duration) -> Self {
+        self.wait_sampling = Some(interval);
+        self
+    }
+
+    /// Emits a single "connection opened" event with the connection fields
+    /// when a connection is established, instead of an `establish` span.
+    ///
+    /// Failures to establish are still logged as errors.
+    #[must_use]
+    pub fn with_establish_event(mut self, event: bool) -> Self {
+        self.establish_event = event;
+        self
+    }
+
+    /// Links the span of every operation on a connection back to the span
+    /// that established it.
+    ///
+    /// This ties operations to their connection without nesting them under a
+    /// long lived parent span. The links are recorded with
+    /// `Span::follows_from`, which `tracing-opentelemetry` exports as span
+    /// links. To stay linkable the establish span is only closed when the
+    /// connection is dropped, though it is only entered while establishing.
+    /// This has no effect with `with_establish_event`.
+    #[must_use]
+    pub fn with_establish_links(mut self, link: bool) -> Self {
+        self.establish_links = link;
+        self
+    }
+
+    /// Records the connection limit of the connecting role and how many
+    /// connections it has open on the establish span, as
+    /// `db.postgresql.role.connection_limit` and
+    /// `db.postgresql.role.connections`.
+    ///
+    /// A limit of `-1` means the role is unlimited. Failures to establish
+    /// caused by a role, database or server connection limit are recorded as
+    /// `db.error.cause` whether or not this is enabled.
+    #[must_use]
+    pub fn with_role_connection_limit(mut self, record: bool) -> Self {
+        self.role_connection_limit = record;
+        self
+    }
+
+    /// Records a fingerprint of each statement as `db.query.fingerprint`.
+    ///
+    /// Statements are normalized the way `pg_stat_statements` normalizes
+    /// them, replacing constants with parameters and squashing lists of
+    /// constants, so that statements grouped together by the server share a
+    /// fingerprint. The fingerprint is the 64-bit FNV-1a hash of the
+    /// normalized statement in hexadecimal. Like `with_page_full`, this costs
+    /// formatting each query.
+    ///
+    /// Fingerprints are recorded by every backend, so spans can be grouped
+    /// by the shape of their statement without recording the statement
+    /// itself, though `mysql` and `sqlite` statements are normalized by the
+    /// rules of `postgres`.
+    #[must_use]
+    pub fn with_query_fingerprint(mut self, record: bool) -> Self {
+        self.query_fingerprint = record;
+        self
+    }
+
+    /// Warns before running a statement whose number of binds does not match
+    /// the placeholders in it.
+    ///
+    /// diesel's query builder always numbers placeholders to match the binds,
+    /// but a raw `sql_query` can get them wrong, which the server only
+    /// reports as an error about the prepared statement. The warning records
+    /// both counts as `db.statement.placeholders` and `db.statement.binds`.
+    /// This costs formatting and serializing the binds of each query.
+    #[must_use]
+    pub fn with_bind_check(mut self, check: bool) -> Self {
+        self.bind_check = check;
+        self
+    }
+
+    /// Records the version of this crate as `diesel_tracing.version`, and
+    /// diesel's name for the backend as `db.backend`, when connections are
+    /// established.
+    ///
+    /// These are fixed when the crate is built, which makes them useful for
+    /// telling apart the binaries producing traces during a rolling upgrade.
+    #[must_use]
+    pub fn with_build_info(mut self, record: bool) -> Self {
+        self.build_info = record;
+        self
+    }
+
+    /// Records a coarse hint of how complex each statement is as
+    /// `db.query.complexity`, one of `low`, `medium` or `high`.
+    ///
+    /// The hint counts the joins, subqueries, groupings and window functions
+    /// in the statement, to help triage slow queries. It is a heuristic
+    /// rather than a measure of cost, and costs formatting each query.
+    #[must_use]
+    pub fn with_query_complexity(mut self, record: bool) -> Self {
+        self.query_complexity = record;
+        self
+    }
+
+    /// Sets the size of the prepared statement cache of each connection when
+    /// it is established.
+    ///
+    /// The size is recorded on the establish span and the spans of typed
+    /// queries as `db.statement_cache.size`, which is `unbounded` unless
+    /// configured otherwise. Changing the size of an established connection with
+    /// `Connection::set_prepared_statement_cache_size` emits an event with the
+    /// new size.
+    #[must_use]
+    pub fn with_prepared_statement_cache_size(mut self, size: CacheSize) -> Self {
+        self.statement_cache_size = Some(size);
+        self
+    }
+
+    /// Records a correlation id, such as the id of the request being served,
+    /// as `correlation_id` on every span.
+    ///
+    /// `read` is called each time a span is recorded and should return the id
+    /// from wherever the application keeps it, for example a thread local set
+    /// from an `X-Request-ID` header, or `None` outside of a request. It is
+    /// called often, so should be cheap. Unlike trace ids, the id is only
+    /// recorded on the spans and not propagated.
+    #[must_use]
+    pub fn with_correlation_id<F>(mut self, read: F) -> Self
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.correlation_id = Some(Hook(Arc::new(read)));
+        self
+    }
+
+    /// Checks the maintenance statistics of the table each statement operates
+    /// on, recording `db.table.dead_tuples` and `db.table.last_analyze_age`,
+    /// in seconds, when the table has more dead tuples than autovacuum should
+    /// have allowed.
+    ///
+    /// A warning is logged for tables that are mostly dead tuples. This is a
+    /// diagnostic mode: the statistics are looked up from
+    /// `pg_stat_user_tables` on the same connection, at most once a minute
+    /// for each table, and statements have to be formatted to find their
+    /// table.
+    #[must_use]
+    pub fn with_table_stats(mut self, check: bool) -> Self {
+        self.table_stats = check;
+        self
+    }
+
+    /// Records the partition or shard a statement targets as
+    /// `db.partition_key`.
+    ///
+    /// Which partition a statement targets depends on the application's
+    /// partitioning scheme, so `extract` works it out from the rendered
+    /// statement, which ends with its binds as in `-- binds: [42, "eu"]`, and
+    /// returns `None` for statements without a partition key. It is called
+    /// for every statement, which has to be rendered for it.
+    #[must_use]
+    pub fn with_partition_key<F>(mut self, extract: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.partition_key = Some(Hook(Arc::new(extract)));
+        self
+    }
+
+    /// Measures the rows loaded by each query, recording the size of the
+    /// largest as `db.response.max_row_bytes` and logging a warning when it
+    /// is over `threshold` bytes.
+    ///
+    /// This is a diagnostic mode for finding queries that accidentally select
+    /// huge values, such as a large `jsonb` or `bytea` column. Sizes are of
+    /// the values as sent by the server, which is every column of every row
+    /// adding up.
+    #[must_use]
+    pub fn with_max_row_bytes(mut self, threshold: usize) -> Self {
+        self.max_row_bytes = Some(threshold);
+        self
+    }
+
+    /// Records an estimate of the time the server spent on reads taking at
+    /// least `threshold`, as seen by the client, as `db.server.duration_ms`.
+    ///
+    /// The estimate comes from reading the server's `clock_timestamp()`
+    /// before and after the read, less the round trips of those readings, and
+    /// tells a slow query apart from a slow network or a large result without
+    /// running `EXPLAIN ANALYZE`. Only reads in a transaction are measured, so
+    /// that the clock is read on the same server connection. Every such read
+    /// costs an extra round trip to the server, and those over the threshold
+    /// a second one.
+    #[must_use]
+    pub fn with_server_duration(mut self, threshold: Duration) -> Self {
+        self.server_duration = Some(threshold);
+        self
+    }
+
+    /// Records the text of statements as `db.statement`, as much of it as
+    /// `policy` allows.
+    ///
+    /// Typed queries are rendered with `diesel::debug_query`, which appends
+    /// their bind values, and raw statements such as those run with
+    /// `batch_execute` are taken as they are. Statements may contain
+    /// sensitive information, so they are omitted by default, and
+    /// `StatementPolicy::Sanitized` strips their literals and binds.
+    #[must_use]
+    pub fn with_statement_policy(mut self, policy: StatementPolicy) -> Self {
+        self.statement_policy = policy;
+        self
+    }
+
+    /// Sets when the connection information is queried from the server.
+    ///
+    /// Querying it takes a round trip while establishing the connection,
+    /// which `InfoQuery::Deferred` moves to the first operation and
+    /// `InfoQuery::Skip` avoids altogether, for connections through poolers
+    /// that the query adds too much latency to or that cannot run it. Wait
+    /// sampling needs the information, so connections without it are not
+    /// sampled.
+    #[must_use]
+    pub fn with_info_query(mut self, query: InfoQuery) -> Self {
+        self.info_query = query;
+        self
+    }
+
+    /// Queries the connection information of postgres connections again after
+    /// each successful ping, and before the next operation after the server
+    /// closes the connection, so that the fields recorded follow failovers.
+    ///
+    /// `InstrumentedPgConnection::refresh_info` queries it on demand. This
+    /// costs a round trip per ping, and has no effect with `InfoQuery::Skip`.
+    #[must_use]
+    pub fn with_info_refresh(mut self, refresh: bool) -> Self {
+        self.info_refresh = refresh;
+        self
+    }
+
+    /// Names the fields recorded on spans after `conventions`, the legacy
+    /// names by default.
+    ///
+    /// This renames the fields recorded once operations finish, including the
+    /// database name, the server's address and port and the statement, but
+    /// not `db.system`, the fields mirrored into logs or metric labels.
+    #[must_use]
+    pub fn with_semantic_conventions(mut self, conventions: SemanticConventions) -> Self {
+        self.semantic_conventions = conventions;
+        self
+    }
+
+    /// Appends a comment in the SQLCommenter format to the statements run by
+    /// `InstrumentedPgConnection`, tagging them with `application` and the W3C
+    /// `traceparent` of the trace they were run in, so that entries in the
+    /// server's logs and `pg_stat_activity` can be traced back to the request
+    /// that ran them.
+    ///
+    /// `traceparent` is called in the span of each statement as it is about
+    /// to run and should return the `traceparent` of the current context, for
+    /// example by injecting it with the `OpenTelemetry` propagator, or `None`
+    /// to tag the statement with the application alone. The text of commented
+    /// statements changes with every trace, so typed queries are no longer
+    /// cached as prepared statements.
+    #[must_use]
+    pub fn with_sql_commenter<F>(mut self, application: impl Into<String>, traceparent: F) -> Self
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.sql_commenter = Some(SqlCommenter {
+            application: application.into(),
+            traceparent: Hook(Arc::new(traceparent)),
+        });
+        self
+    }
+
+    /// Names the pool the connections are in, recorded as `db.pool.name` on
+    /// the spans of async connections, so that the queries of different pools
+    /// to the same database can be told apart.
+    #[must_use]
+    pub fn with_pool_name(mut self, name: impl Into<String>) -> Self {
+        self.pool_name = Some(name.into());
+        self
+    }
+
+    /// Sets the level of the spans of operations run on established
+    /// connections, `DEBUG` by default.
+    ///
+    /// Services running many queries can keep these at `DEBUG`, or lower them
+    /// to `TRACE`, while keeping rarer spans such as those of establishing
+    /// connections at a level they record.
+    #[must_use]
+    pub fn with_query_span_level(mut self, level: SpanLevel) -> Self {
+        self.query_span_level = level;
+        self
+    }
+
+    /// Sets the level of the spans of establishing connections, `DEBUG` by
+    /// default.
+    #[must_use]
+    pub fn with_establish_span_level(mut self, level: SpanLevel) -> Self {
+        self.establish_span_level = level;
+        self
+    }
+
+    /// Sets the target of the spans of postgres connections, the module path
+    /// of the crate by default, so that the connections to each logical
+    /// database can be filtered apart, with `EnvFilter` directives such as
+    /// `db.orders=info,db.analytics=off`.
+    ///
+    /// Only the spans of operations and of establishing connections take the
+    /// target; the events logged for them keep the crate's.
+    #[must_use]
+    pub fn with_span_target(mut self, target: &'static str) -> Self {
+        self.span_target = Some(target);
+        self
+    }
+
+    /// Records `system` as the `db.system` of postgres connections, in place
+    /// of the one detected from the version of the server.
+    ///
+    /// `CockroachDB`, Redshift and `YugabyteDB` are told apart from postgres
+    /// by the versions they report, but compatible databases reporting the
+    /// version of postgres they are based on, such as `AlloyDB`, are recorded
+    /// as `postgresql` unless they are named here.
+    #[must_use]
+    pub fn with_db_system(mut self, system: &'static str) -> Self {
+        self.db_system = Some(system);
+        self
+    }
+
+    /// Calls `hook` before each query of the connection is run, with the
+    /// operation and statement of the query where they are known.
+    ///
+    /// See the `hooks` module.
+    #[must_use]
+    pub fn with_query_start_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&QueryStart<'_>) + Send + Sync + 'static,
+    {
+        self.query_start_hook = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Calls `hook` once each query of the connection has finished, with how
+    /// long it took, the rows it returned or affected and the error it failed
+    /// with, if any.
+    ///
+    /// See the `hooks` module.
+    #[must_use]
+    pub fn with_query_end_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&QueryEnd<'_>) + Send + Sync + 'static,
+    {
+        self.query_end_hook = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Runs the statements of a `batch_execute` one at a time, each in a
+    /// `batch_statement` span nested in the span of the batch recording its
+    /// position in the batch as `db.statement.index`, its `db.operation` and
+    /// its `db.statement` as the statement policy allows, so that the slow or
+    /// failing statement of a migration can be found.
+    ///
+    /// The statements of a batch are otherwise sent together, which the
+    /// server runs in a single implicit transaction. Run one at a time, the
+    /// statements before a failing one stay committed unless the batch runs
+    /// in a transaction, as diesel's migrations do.
+    #[must_use]
+    pub fn with_batch_statement_spans(mut self, enabled: bool) -> Self {
+        self.batch_statements = enabled;
+        self
+    }
+
+    /// Adds an attribute set to `value` on every span of the connection, for
+    /// deployment specific tags such as `db.cluster` or `peer.service`.
+    ///
+    /// `tracing` only records fields declared when a span is created, so the
+    /// attributes are set through `tracing-opentelemetry` and only reach the
+    /// `OpenTelemetry` spans, not other subscribers or the mirrored log
+    /// fields. They do not count towards the field budget.
+    #[cfg(feature = "opentelemetry")]
+    #[must_use]
+    pub fn with_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes
+            .push(opentelemetry::KeyValue::new(key.into(), value.into()));
+        self
+    }
+
+    /// Logs a `slow query` warning for operations taking `threshold` or
+    /// longer, with the time they took as `db.duration_ms` and their
+    /// statement if statements are recorded.
+    ///
+    /// This applies to the connections of every backend. The operations of
+    /// async connections are timed from when they are first polled, and are
+    /// also reported if they are dropped after taking too long, with
+    /// `db.cancelled`.
+    #[must_use]
+    pub fn with_slow_query_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_query = Some(threshold);
+        self
+    }
+
+    /// Records the spans of only one in every `one_in` queries run on each
+    /// connection, starting with its first, to cut the cost of tracing
+    /// connections running many small queries.
+    ///
+    /// The queries left out run without a span of their own, and their slow
+    /// queries are warned about in the span they were run from, but a query
+    /// left out that fails still gets its span, made once it has failed, so
+    /// that failures are always recorded. This applies to the spans of
+    /// `batch_execute`, `execute_returning_count` and `load` on the
+    /// connections of every backend, not to those of transactions or of
+    /// establishing connections.
+    #[must_use]
+    pub fn with_query_sampling(mut self, one_in: u32) -> Self {
+        self.query_sampling = Some(one_in);
+        self
+    }
+
+    /// Plans the `SELECT` statements of `InstrumentedPgConnection` that reach
+    /// the slow query threshold again with `EXPLAIN (FORMAT JSON)`, logging
+    /// the plan as `db.plan` in a `slow query plan` event in their span.
+    ///
+    /// The statement is planned but not run again, and planning it is given
+    /// up on after `timeout`. Typed queries have to be formatted to be
+    /// planned, and their binds are not kept once they have run, so those with
+    /// binds are planned as generic plans, which PostgreSQL 16 added. This
+    /// only applies with a slow query threshold.
+    #[must_use]
+    pub fn with_slow_query_explain(mut self, timeout: Duration) -> Self {
+        self.slow_query_explain = Some(timeout);
+        self
+    }
+
+    /// Makes this the configuration of the connections established or
+    /// wrapped without one, such as through `diesel::Connection::establish`,
+    /// in place of the default configuration.
+    ///
+    /// The global configuration is meant to be set once at startup, before
+    /// connecting, and connections given a configuration of their own
+    /// ignore it.
+    ///
+    /// # Errors
+    ///
+    /// Hands the configuration back if the global configuration has
+    /// already been set.
+    pub fn set_global(self) -> Result<(), TracingConfig> {
+        GLOBAL.set(self)
+    }
+
+    /// The configuration of the connections established or wrapped without
+    /// one: the global configuration if it has been set, and the default
+    /// configuration otherwise.
+    #[must_use]
+    pub fn global() -> TracingConfig {
+        GLOBAL.get().cloned().unwrap_or_default()
+    }
+
+    /// Overrides options with the `DIESEL_TRACING_*` environment variables
+    /// that are set, so that deployments can change them without a change
+    /// to the code:
+    ///
+    /// - `DIESEL_TRACING_STATEMENTS`, the statement policy, as `omit`,
+    ///   `sanitized` or `full`
+    /// - `DIESEL_TRACING_SLOW_QUERY_MS`, the slow query threshold in
+    ///   milliseconds
+    /// - `DIESEL_TRACING_SPAN_LEVEL`, the level of the spans of operations,
+    ///   as `trace`, `debug` or `info`
+    /// - `DIESEL_TRACING_SAMPLING`, the sampling of query spans, recording
+    ///   one in every so many
+    ///
+    /// Variables set to values that cannot be parsed are ignored with a
+    /// warning. Options set after this override the environment in turn.
+    #[must_use]
+    pub fn with_env(self) -> Self {
+        self.with_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Overrides options with the variables read by `var`, as `with_env`
+    /// does with the environment.
+    fn with_vars(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+        if let Some(policy) = env_var(&var, "DIESEL_TRACING_STATEMENTS", |value| match value {
+            "omit" => Some(StatementPolicy::Omit),
+            "sanitized" => Some(StatementPolicy::Sanitized),
+            "full" => Some(StatementPolicy::Full),
+            _ => None,
+        }) {
+            self.statement_policy = policy;
+        }
+        if let Some(millis) = env_var(&var, "DIESEL_TRACING_SLOW_QUERY_MS", |value| {
+            value.parse().ok()
+        }) {
+            self.slow_query = Some(Duration::from_millis(millis));
+        }
+        if let Some(level) = env_var(&var, "DIESEL_TRACING_SPAN_LEVEL", |value| match value {
+            "trace" => Some(SpanLevel::Trace),
+            "debug" => Some(SpanLevel::Debug),
+            "info" => Some(SpanLevel::Info),
+            _ => None,
+        }) {
+            self.query_span_level = level;
+        }
+        if let Some(one_in) = env_var(&var, "DIESEL_TRACING_SAMPLING", |value| value.parse().ok()) {
+            self.query_sampling = Some(one_in);
+        }
+        self
+    }
+}
+
+/// The variable `name` read by `var`, trimmed, lowercased and parsed with
+/// `parse`, warning if it is set to a value that cannot be parsed.
+fn env_var<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> Option<T> {
+    let value = var(name)?;
+    let parsed = parse(&value.trim().to_ascii_lowercase());
+    if parsed.is_none() {
+        warn!(variable = name, %value, "ignoring invalid environment variable");
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_options_are_read_from_variables() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let vars: HashMap<_, _> = [
+            ("DIESEL_TRACING_STATEMENTS", " Sanitized "),
+            ("DIESEL_TRACING_SLOW_QUERY_MS", "250"),
+            ("DIESEL_TRACING_SPAN_LEVEL", "info"),
+            ("DIESEL_TRACING_SAMPLING", "often"),
+        ]
+        .iter()
+        .copied()
+        .collect();
+        let config = TracingConfig::default()
+            .with_query_sampling(10)
+            .with_vars(|name| vars.get(name).map(ToString::to_string));
+
+        assert_eq!(config.statement_policy, StatementPolicy::Sanitized);
+        assert_eq!(config.slow_query, Some(Duration::from_millis(250)));
+        assert_eq!(config.query_span_level, SpanLevel::Info);
+        assert_eq!(config.query_sampling, Some(10));
+        let event = capture.event("ignoring invalid environment variable");
+        assert_eq!(event.field("variable"), Some("DIESEL_TRACING_SAMPLING"));
+    }
+}
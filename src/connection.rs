@@ -0,0 +1,462 @@
+/*!
+A connection wrapper that instruments any diesel connection.
+
+[`InstrumentedConnection`] records the fields common to every backend on the
+spans of the operations it runs, working out `db.system` from the backend,
+so connections of backends this crate knows nothing about can still be
+instrumented. The wrappers of the backend modules record more, such as the
+database name and server address, which need queries specific to the
+backend.
+*/
+use crate::config::TracingConfig;
+use crate::fields::SpanFields;
+use crate::hooks;
+#[cfg(feature = "metrics")]
+use crate::meter;
+use crate::query::RowCount;
+use crate::sampling::{QuerySpan, SpanSampler};
+use crate::slow;
+use crate::summary;
+use crate::traced;
+use diesel::connection::{
+    AnsiTransactionManager, CacheSize, Connection, Instrumentation, LoadConnection,
+    SimpleConnection, TransactionManager, TransactionManagerStatus,
+};
+use diesel::expression::QueryMetadata;
+use diesel::migration::MigrationConnection;
+use diesel::query_builder::{Query, QueryFragment, QueryId};
+use diesel::result::{ConnectionResult, Error, QueryResult};
+use tracing::{debug, error, field, info, Span};
+
+/// The `db.system` of the backend `B`, going by the name of its type, or
+/// `other_sql` for backends without a name in the semantic conventions.
+pub(crate) fn db_system<B>() -> &'static str {
+    let name = std::any::type_name::<B>();
+    match name.rsplit("::").next().unwrap_or(name) {
+        "Pg" => "postgresql",
+        "Mysql" => "mysql",
+        "Sqlite" => "sqlite",
+        _ => "other_sql",
+    }
+}
+
+/// Adds the depth of the transaction `conn` is in, if it is in one, as
+/// `db.transaction.depth` and the status of the transaction as
+/// `db.transaction.status` to `fields`.
+///
+/// The status is `none` outside of a transaction, `active` within one and
+/// `broken` once the transaction can only be rolled back, because a statement
+/// in it has failed, which only the caller can tell, or because its
+/// transaction manager is in error.
+pub(crate) fn record_transaction<C>(conn: &mut C, failed: bool, fields: &mut SpanFields<'_>)
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    let status = match AnsiTransactionManager::transaction_manager_status_mut(conn) {
+        TransactionManagerStatus::InError => "broken",
+        TransactionManagerStatus::Valid(valid) => match valid.transaction_depth() {
+            None => "none",
+            Some(depth) => {
+                fields.push("db.transaction.depth", depth.get());
+                if failed {
+                    "broken"
+                } else {
+                    "active"
+                }
+            }
+        },
+    };
+    fields.push("db.transaction.status", status);
+}
+
+/// `size` as recorded in `db.statement_cache.size`.
+pub(crate) fn cache_size_name(size: CacheSize) -> &'static str {
+    if matches!(size, CacheSize::Disabled) {
+        "disabled"
+    } else {
+        "unbounded"
+    }
+}
+
+/// Creates the span for an operation, with every optional field left empty
+/// until the operation has finished.
+macro_rules! connection_span {
+    ($level:expr, $name:literal, $system:expr) => {
+        span_at!(
+            $level,
+            $name,
+            db.system = $system,
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            otel.status_description = field::Empty,
+            correlation_id = field::Empty,
+            db.response.rows = field::Empty,
+            db.result = field::Empty,
+            otel.name = field::Empty,
+            db.operation.name = field::Empty,
+            db.test_transaction = field::Empty,
+            db.transaction.depth = field::Empty,
+            db.transaction.status = field::Empty,
+        )
+    };
+}
+
+/// Any diesel connection, recording the fields common to every backend on
+/// the spans of its operations.
+///
+/// Transactions are managed by diesel's `AnsiTransactionManager`, as they are
+/// for the connections of all of diesel's backends.
+pub struct InstrumentedConnection<C> {
+    inner: C,
+    config: TracingConfig,
+    span_sampler: SpanSampler,
+    // whether a test transaction has begun, which is never committed
+    test_transaction: bool,
+}
+
+impl<C> InstrumentedConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    /// Instruments an established connection.
+    ///
+    /// Only the field budget, the fields mirrored into logs, the correlation
+    /// id, the span levels, the slow query threshold, query sampling and the
+    /// size of the prepared statement cache of `config` apply to these
+    /// connections.
+    #[must_use]
+    pub fn new(mut inner: C, config: TracingConfig) -> Self {
+        if let Some(size) = config.statement_cache_size {
+            inner.set_prepared_statement_cache_size(size);
+        }
+        InstrumentedConnection {
+            inner,
+            config,
+            span_sampler: SpanSampler::default(),
+            test_transaction: false,
+        }
+    }
+
+    /// The wrapped connection, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced.
+    #[must_use]
+    pub fn as_inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// The wrapped connection, mutably, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced, and transactions should
+    /// not be started or ended through it as the wrapper would not know of
+    /// them.
+    pub fn as_inner_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwraps the connection, which is no longer traced.
+    #[must_use]
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// Establishes a connection, recording telemetry as described by `config`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection cannot be established.
+    pub fn establish_with_config(
+        database_url: &str,
+        config: TracingConfig,
+    ) -> ConnectionResult<Self> {
+        let span = span_at!(
+            config.establish_span_level,
+            "establish_with_config",
+            db.system = db_system::<C::Backend>(),
+            otel.kind = "client",
+            correlation_id = field::Empty,
+        );
+        let _entered = span.enter();
+        let result = Self::establish_recorded(database_url, config);
+        if let Err(e) = &result {
+            error!(error = %e);
+        }
+        result
+    }
+
+    /// Establishes a connection, recording what is known about it on the
+    /// current span.
+    fn establish_recorded(database_url: &str, config: TracingConfig) -> ConnectionResult<Self> {
+        debug!("establishing connection");
+        let conn = InstrumentedConnection::new(C::establish(database_url)?, config);
+        conn.record_fields(&Span::current(), SpanFields::default());
+
+        Ok(conn)
+    }
+
+    /// Runs `f` against the inner connection inside `span`, recording the
+    /// outcome once it has returned.
+    fn in_span<R: RowCount>(
+        &mut self,
+        span: &mut QuerySpan<'_>,
+        read: bool,
+        f: impl FnOnce(&mut C) -> QueryResult<R>,
+    ) -> QueryResult<R> {
+        let mut fields = SpanFields::default();
+        record_transaction(&mut self.inner, false, &mut fields);
+
+        let entered = span.enter();
+        hooks::start(&self.config, db_system::<C::Backend>(), &fields);
+        let start = std::time::Instant::now();
+        let result = traced::named(&mut fields, || f(&mut self.inner));
+        let elapsed = start.elapsed();
+        drop(entered);
+        let span = span.outcome(result.is_err());
+        let _entered = span.enter();
+        summary::record(elapsed, result.is_err());
+        #[cfg(feature = "metrics")]
+        meter::query(
+            db_system::<C::Backend>(),
+            None,
+            span,
+            elapsed,
+            result.is_err(),
+        );
+        slow::check(&self.config, elapsed, None::<&str>);
+
+        if let Some(rows) = result.as_ref().ok().and_then(RowCount::row_count) {
+            fields.push("db.response.rows", rows);
+            if read {
+                fields.push("db.result", if rows == 0 { "empty" } else { "rows" });
+            }
+        }
+        let error = result.as_ref().err();
+        fields.push_status(error);
+        let fields = self.record_fields(span, fields);
+        fields.log(&self.config, error);
+        hooks::end(
+            &self.config,
+            db_system::<C::Backend>(),
+            &fields,
+            elapsed,
+            error,
+        );
+
+        result
+    }
+
+    /// Completes `fields` with the connection fields, records them on `span`
+    /// and returns them.
+    fn record_fields<'a>(&'a self, span: &Span, mut fields: SpanFields<'a>) -> SpanFields<'a> {
+        if let Some(id) = self.config.correlation_id() {
+            fields.push("correlation_id", id);
+        }
+        if self.test_transaction {
+            fields.push("db.test_transaction", true);
+        }
+        fields.record(span, &self.config);
+        fields
+    }
+}
+
+impl<C> SimpleConnection for InstrumentedConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        let level = self.config.query_span_level;
+        let mut span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "batch_execute", db_system::<C::Backend>())
+        });
+        self.in_span(&mut span, false, |conn| {
+            debug!("executing batch query");
+            conn.batch_execute(query)
+        })
+    }
+}
+
+impl<C> Connection for InstrumentedConnection<C>
+where
+    C: Connection<TransactionManager = AnsiTransactionManager>,
+{
+    type Backend = C::Backend;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        Self::establish_with_config(database_url, TracingConfig::global())
+    }
+
+    #[doc(hidden)]
+    fn execute_returning_count<T>(&mut self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Self::Backend> + QueryId,
+    {
+        let level = self.config.query_span_level;
+        let mut span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "execute_returning_count", db_system::<C::Backend>())
+        });
+        self.in_span(&mut span, false, |conn| {
+            debug!("executing returning count");
+            conn.execute_returning_count(source)
+        })
+    }
+
+    fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<Error>,
+    {
+        let span = connection_span!(
+            self.config.query_span_level,
+            "transaction",
+            db_system::<C::Backend>()
+        );
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        debug!("running transaction");
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = Self::TransactionManager::transaction(self, f);
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        #[cfg(feature = "metrics")]
+        meter::transaction(
+            db_system::<C::Backend>(),
+            None,
+            start.elapsed(),
+            if result.is_err() {
+                "rollback"
+            } else {
+                "commit"
+            },
+        );
+        result
+    }
+
+    fn begin_test_transaction(&mut self) -> QueryResult<()> {
+        let mut span = QuerySpan::from(connection_span!(
+            self.config.query_span_level,
+            "begin_test_transaction",
+            db_system::<C::Backend>()
+        ));
+        self.test_transaction = true;
+        self.in_span(&mut span, false, |conn| {
+            debug!("beginning test transaction");
+            conn.begin_test_transaction()
+        })
+    }
+
+    #[doc(hidden)]
+    fn transaction_state(
+        &mut self,
+    ) -> &mut <Self::TransactionManager as TransactionManager<Self>>::TransactionStateData {
+        self.inner.transaction_state()
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        self.inner.instrumentation()
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.inner.set_instrumentation(instrumentation);
+    }
+
+    fn set_prepared_statement_cache_size(&mut self, size: CacheSize) {
+        let span = connection_span!(
+            self.config.query_span_level,
+            "set_prepared_statement_cache_size",
+            db_system::<C::Backend>()
+        );
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        info!(
+            db.statement_cache.size = cache_size_name(size),
+            "prepared statement cache size changed",
+        );
+        self.inner.set_prepared_statement_cache_size(size);
+    }
+}
+
+impl<C> MigrationConnection for InstrumentedConnection<C>
+where
+    C: MigrationConnection<TransactionManager = AnsiTransactionManager>,
+{
+    fn setup(&mut self) -> QueryResult<usize> {
+        self.inner.setup()
+    }
+}
+
+impl<C> LoadConnection for InstrumentedConnection<C>
+where
+    C: LoadConnection<TransactionManager = AnsiTransactionManager>,
+{
+    // the rows are collected to count them
+    type Cursor<'conn, 'query> = std::vec::IntoIter<QueryResult<Self::Row<'conn, 'query>>>;
+    type Row<'conn, 'query> = C::Row<'conn, 'query>;
+
+    fn load<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> QueryResult<Self::Cursor<'conn, 'query>>
+    where
+        T: Query + QueryFragment<Self::Backend> + QueryId + 'query,
+        Self::Backend: QueryMetadata<T::SqlType>,
+    {
+        let level = self.config.query_span_level;
+        let mut span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "load", db_system::<C::Backend>())
+        });
+        self.in_span(&mut span, true, |conn| {
+            debug!("loading rows");
+            Ok(conn.load(source)?.collect::<Vec<_>>())
+        })
+        .map(Vec::into_iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db_system_of_backends() {
+        struct Custom;
+
+        #[cfg(feature = "postgres")]
+        assert_eq!(db_system::<diesel::pg::Pg>(), "postgresql");
+        #[cfg(feature = "mysql")]
+        assert_eq!(db_system::<diesel::mysql::Mysql>(), "mysql");
+        #[cfg(feature = "sqlite")]
+        assert_eq!(db_system::<diesel::sqlite::Sqlite>(), "sqlite");
+        assert_eq!(db_system::<Custom>(), "other_sql");
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn test_generic_connection_is_instrumented() {
+        use crate::test::Capture;
+        use diesel::sql_types::Integer;
+        use diesel::{select, IntoSql, RunQueryDsl};
+
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let mut conn = InstrumentedConnection::<diesel::PgConnection>::establish(&url)
+            .expect("failed to establish connection");
+        select(1.into_sql::<Integer>())
+            .get_result::<i32>(&mut conn)
+            .expect("failed to select");
+
+        let span = capture.span("load");
+        assert_eq!(span.field("db.system"), Some("postgresql"));
+        assert_eq!(span.field("db.response.rows"), Some("1"));
+
+        assert!(conn.batch_execute("SELECT * FROM no_such_table").is_err());
+        assert_eq!(
+            capture.span("batch_execute").field("otel.status_code"),
+            Some("ERROR")
+        );
+    }
+}
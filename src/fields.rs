@@ -0,0 +1,605 @@
+use crate::config::{SemanticConventions, TracingConfig};
+use diesel::result::Error;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use tracing::{field, Span};
+
+/// Optional fields from most to least important. When a field budget is
+/// configured the fields earlier in this list are the ones that get recorded.
+const PRIORITY: &[&str] = &[
+    "db.error.code",
+    "db.error.cause",
+    "otel.status_code",
+    "otel.status_description",
+    "db.system",
+    "correlation_id",
+    "db.test_transaction",
+    "db.name",
+    "db.user",
+    "db.pool.name",
+    "db.connection.id",
+    "db.connection_string",
+    "db.postgresql.backend_pid",
+    "db.client.application_name",
+    "db.search_path",
+    "db.partition_key",
+    "db.query_budget.exceeded",
+    "db.transaction.outcome",
+    "db.transaction.depth",
+    "db.transaction.status",
+    "db.savepoint.name",
+    "db.transaction.rollback_only",
+    "db.transaction.statements",
+    "db.transaction.rows",
+    "db.transaction.db_time_ms",
+    "db.advisory_lock.key",
+    "db.advisory_lock.acquired",
+    "db.advisory_lock.released",
+    "db.advisory_lock.wait_ms",
+    "db.transaction.isolation_level",
+    "db.transaction.read_only",
+    "db.transaction.deferrable",
+    "net.peer.ip",
+    "net.peer.name",
+    "net.peer.port",
+    "server.address",
+    "server.port",
+    "net.transport",
+    "net.sock.family",
+    "net.sock.path",
+    "db.connection.encrypted",
+    "tls.protocol.version",
+    "tls.cipher",
+    "db.response.rows",
+    "db.response.first_row_ms",
+    "db.response.max_row_bytes",
+    "db.response.error_row",
+    "db.response.error_column",
+    "db.command_tag",
+    "db.query.fingerprint",
+    "db.query.complexity",
+    "db.statement.deallocated",
+    "db.operation",
+    "db.sql.table",
+    "otel.name",
+    "db.operation.name",
+    "db.operation.batch.size",
+    "db.statement.name",
+    "db.proxy",
+    "db.version",
+    "db.postgresql.max_connections",
+    "db.postgresql.role.connection_limit",
+    "db.postgresql.role.connections",
+    "db.statement_cache.size",
+    "db.statement_cache.hit",
+    "db.statement_cache.key",
+    "diesel_tracing.version",
+    "db.backend",
+    "db.table.dead_tuples",
+    "db.table.last_analyze_age",
+    "db.server.duration_ms",
+    "db.wait.lock_ms",
+    "db.wait.io_ms",
+    "db.query.boxed",
+    "db.result",
+    "db.result.page_full",
+    "db.statement",
+];
+
+fn priority(name: &str) -> usize {
+    PRIORITY
+        .iter()
+        .position(|field| *field == name)
+        .unwrap_or(PRIORITY.len())
+}
+
+pub(crate) enum FieldValue<'a> {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    Str(Cow<'a, str>),
+}
+
+impl From<bool> for FieldValue<'_> {
+    fn from(value: bool) -> Self {
+        FieldValue::Bool(value)
+    }
+}
+
+impl From<i32> for FieldValue<'_> {
+    fn from(value: i32) -> Self {
+        FieldValue::I64(value.into())
+    }
+}
+
+impl From<i64> for FieldValue<'_> {
+    fn from(value: i64) -> Self {
+        FieldValue::I64(value)
+    }
+}
+
+impl From<u32> for FieldValue<'_> {
+    fn from(value: u32) -> Self {
+        FieldValue::U64(value.into())
+    }
+}
+
+impl From<u64> for FieldValue<'_> {
+    fn from(value: u64) -> Self {
+        FieldValue::U64(value)
+    }
+}
+
+impl From<usize> for FieldValue<'_> {
+    fn from(value: usize) -> Self {
+        FieldValue::U64(value as u64)
+    }
+}
+
+impl<'a> From<&'a str> for FieldValue<'a> {
+    fn from(value: &'a str) -> Self {
+        FieldValue::Str(Cow::Borrowed(value))
+    }
+}
+
+impl From<String> for FieldValue<'_> {
+    fn from(value: String) -> Self {
+        FieldValue::Str(Cow::Owned(value))
+    }
+}
+
+impl fmt::Display for FieldValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Bool(value) => value.fmt(f),
+            FieldValue::I64(value) => value.fmt(f),
+            FieldValue::U64(value) => value.fmt(f),
+            FieldValue::Str(value) => value.fmt(f),
+        }
+    }
+}
+
+/// Emits an event with every optional field that can be mirrored into logs,
+/// each being recorded only when `$get` returns a value for it.
+///
+/// Like the spans, events need their fields to be known up front, so this
+/// has to list every field in `PRIORITY` that is recorded on operations.
+/// Fields only recorded when establishing a connection are left out, as
+/// tracing limits the number of fields an event can have, and so are those
+/// of diagnostic modes that log a warning of their own and the settings of
+/// transactions started with a builder, and the identifiers of the
+/// connection, which are on its spans. The fields only recorded on success
+/// are passed in by the successful outcome, leaving room on error events for
+/// the details of the error.
+macro_rules! mirrored_event {
+    ($level:ident, $get:ident, $($rest:tt)*) => {
+        tracing::$level!(
+            db.error.code = $get("db.error.code"),
+            correlation_id = $get("correlation_id"),
+            db.name = $get("db.name"),
+            db.search_path = $get("db.search_path"),
+            db.transaction.outcome = $get("db.transaction.outcome"),
+            db.transaction.rollback_only = $get("db.transaction.rollback_only"),
+            net.peer.ip = $get("net.peer.ip"),
+            net.peer.name = $get("net.peer.name"),
+            net.peer.port = $get("net.peer.port"),
+            net.transport = $get("net.transport"),
+            db.response.error_row = $get("db.response.error_row"),
+            db.response.error_column = $get("db.response.error_column"),
+            db.command_tag = $get("db.command_tag"),
+            db.query.fingerprint = $get("db.query.fingerprint"),
+            db.query.complexity = $get("db.query.complexity"),
+            db.statement.deallocated = $get("db.statement.deallocated"),
+            db.operation = $get("db.operation"),
+            db.statement.name = $get("db.statement.name"),
+            db.proxy = $get("db.proxy"),
+            db.version = $get("db.version"),
+            db.partition_key = $get("db.partition_key"),
+            db.server.duration_ms = $get("db.server.duration_ms"),
+            db.wait.lock_ms = $get("db.wait.lock_ms"),
+            db.wait.io_ms = $get("db.wait.io_ms"),
+            db.query.boxed = $get("db.query.boxed"),
+            db.statement = $get("db.statement"),
+            $($rest)*
+        )
+    };
+}
+
+/// Optional fields collected while an operation runs, recorded on its span
+/// once the operation has finished.
+#[derive(Default)]
+pub(crate) struct SpanFields<'a> {
+    fields: Vec<(&'static str, FieldValue<'a>)>,
+}
+
+impl<'a> SpanFields<'a> {
+    pub(crate) fn push(&mut self, name: &'static str, value: impl Into<FieldValue<'a>>) {
+        let value = value.into();
+        match self.fields.iter_mut().find(|(field, _)| *field == name) {
+            Some(existing) => existing.1 = value,
+            None => self.fields.push((name, value)),
+        }
+    }
+
+    /// Collects the outcome of an operation as `otel.status_code`, `OK` or
+    /// `ERROR`, and the error it failed with as `otel.status_description`,
+    /// which `tracing-opentelemetry` sets the status of the span from.
+    pub(crate) fn push_status<E: fmt::Display>(&mut self, error: Option<&E>) {
+        match error {
+            Some(e) => {
+                self.push("otel.status_code", "ERROR");
+                self.push("otel.status_description", e.to_string());
+            }
+            None => self.push("otel.status_code", "OK"),
+        }
+    }
+
+    /// The value collected for the field `name`, if any.
+    pub(crate) fn get(&self, name: &str) -> Option<&FieldValue<'a>> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value)
+    }
+
+    /// The text collected for the field `name`, if any.
+    pub(crate) fn str(&self, name: &str) -> Option<&str> {
+        match self.get(name)? {
+            FieldValue::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The count collected for the field `name`, if any.
+    pub(crate) fn usize(&self, name: &str) -> Option<usize> {
+        match self.get(name)? {
+            FieldValue::U64(value) => usize::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    /// Copies any borrowed values, for fields that outlive what they were
+    /// collected from.
+    pub(crate) fn into_owned(self) -> SpanFields<'static> {
+        SpanFields {
+            fields: self
+                .fields
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        FieldValue::Bool(value) => FieldValue::Bool(value),
+                        FieldValue::I64(value) => FieldValue::I64(value),
+                        FieldValue::U64(value) => FieldValue::U64(value),
+                        FieldValue::Str(value) => FieldValue::Str(Cow::Owned(value.into_owned())),
+                    };
+                    (name, value)
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds every field of `other`, replacing any already collected.
+    pub(crate) fn extend(&mut self, other: SpanFields<'a>) {
+        for (name, value) in other.fields {
+            self.push(name, value);
+        }
+    }
+
+    /// Records the collected fields on `span` in priority order, under the
+    /// names of the semantic conventions of `config`, stopping once as many
+    /// names as the field budget of `config` allows have been recorded, along
+    /// with the static attributes of `config`.
+    pub(crate) fn record(&self, span: &Span, config: &TracingConfig) {
+        #[cfg(feature = "opentelemetry")]
+        config.record_attributes(span);
+
+        let mut fields: Vec<_> = self.fields.iter().collect();
+        fields.sort_by_key(|(name, _)| priority(name));
+
+        // a field recorded under both its legacy and its stable name counts
+        // twice against the budget
+        let renamed = fields.into_iter().flat_map(|(name, value)| {
+            let (legacy, stable) = config.semantic_conventions.names(name);
+            legacy
+                .map(|legacy| (legacy, false))
+                .into_iter()
+                .chain(stable.map(|stable| (stable, true)))
+                .map(move |(renamed, is_stable)| (*name, renamed, is_stable, value))
+        });
+        for (name, renamed, is_stable, value) in
+            renamed.take(config.field_budget.unwrap_or(usize::MAX))
+        {
+            match value {
+                FieldValue::Bool(value) => span.record(renamed, value),
+                FieldValue::I64(value) => span.record(renamed, value),
+                FieldValue::U64(value) => span.record(renamed, value),
+                FieldValue::Str(value) if is_stable => {
+                    span.record(renamed, SemanticConventions::stable_value(name, value))
+                }
+                FieldValue::Str(value) => span.record(renamed, &value.as_ref()),
+            };
+        }
+    }
+
+    /// Emits the outcome of an operation as an event, mirroring the fields
+    /// selected with `TracingConfig::with_log_fields` onto it.
+    ///
+    /// Failures are always logged as errors, successes only when there are
+    /// fields to mirror. Errors reported by the database also record their
+    /// SQLSTATE as `db.response.status_code`, when it is known, and the
+    /// constraint, table and detail the database gave.
+    pub(crate) fn log(&self, config: &TracingConfig, error: Option<&Error>) {
+        let get = |name: &str| {
+            if !config.log_fields.contains(name) {
+                return None;
+            }
+            self.get(name).map(field::display)
+        };
+
+        match error {
+            Some(e) => {
+                let info = match e {
+                    Error::DatabaseError(_, info) => Some(info),
+                    _ => None,
+                };
+                mirrored_event!(
+                    error,
+                    get,
+                    db.response.status_code = self.get("db.error.code").map(field::display),
+                    db.constraint = info.and_then(|info| info.constraint_name()),
+                    db.sql.table = info.and_then(|info| info.table_name()),
+                    db.error.detail = info.and_then(|info| info.details()),
+                    otel.status_code = "ERROR",
+                    error = %e,
+                );
+                // the exception event of the conventions, which exporters
+                // attach to the span, kept out of logs going by level or
+                // target as the error has just been logged
+                #[cfg(feature = "opentelemetry")]
+                tracing::debug!(
+                    target: "diesel_tracing::exception",
+                    exception.type = exception_type(e),
+                    exception.message = %e,
+                    "exception",
+                );
+            }
+            None if !config.log_fields.is_empty() => mirrored_event!(
+                debug,
+                get,
+                db.response.rows = get("db.response.rows"),
+                db.result = get("db.result"),
+                db.result.page_full = get("db.result.page_full"),
+                "database operation completed"
+            ),
+            None => {}
+        }
+    }
+}
+
+/// The variant of `error`, recorded as `exception.type`.
+#[cfg(feature = "opentelemetry")]
+fn exception_type(error: &Error) -> &'static str {
+    match error {
+        Error::InvalidCString(_) => "diesel::result::Error::InvalidCString",
+        Error::DatabaseError(..) => "diesel::result::Error::DatabaseError",
+        Error::NotFound => "diesel::result::Error::NotFound",
+        Error::QueryBuilderError(_) => "diesel::result::Error::QueryBuilderError",
+        Error::DeserializationError(_) => "diesel::result::Error::DeserializationError",
+        Error::SerializationError(_) => "diesel::result::Error::SerializationError",
+        Error::RollbackErrorOnCommit { .. } => "diesel::result::Error::RollbackErrorOnCommit",
+        Error::RollbackTransaction => "diesel::result::Error::RollbackTransaction",
+        Error::AlreadyInTransaction => "diesel::result::Error::AlreadyInTransaction",
+        Error::NotInTransaction => "diesel::result::Error::NotInTransaction",
+        Error::BrokenTransactionManager => "diesel::result::Error::BrokenTransactionManager",
+        _ => "diesel::result::Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+    use tracing::field;
+
+    #[test]
+    fn test_budget_keeps_high_priority_fields() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let span = tracing::debug_span!(
+            "budgeted",
+            db.error.code = field::Empty,
+            db.name = field::Empty,
+            net.peer.port = field::Empty,
+            db.version = field::Empty,
+        );
+        let mut fields = SpanFields::default();
+        fields.push("db.version", "12.4");
+        fields.push("net.peer.port", 5432);
+        fields.push("db.name", "postgres");
+        fields.push("db.error.code", "23505");
+        fields.record(&span, &TracingConfig::default().with_field_budget(2));
+
+        let span = capture.span("budgeted");
+        assert_eq!(span.field("db.error.code"), Some("23505"));
+        assert_eq!(span.field("db.name"), Some("postgres"));
+        assert_eq!(span.field("net.peer.port"), None);
+        assert_eq!(span.field("db.version"), None);
+    }
+
+    #[test]
+    fn test_log_mirrors_selected_fields() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut fields = SpanFields::default();
+        fields.push("db.name", "postgres");
+        fields.push("db.command_tag", "UPDATE 2");
+        fields.log(&TracingConfig::default().with_log_fields(["db.name"]), None);
+
+        let event = capture.event("database operation completed");
+        assert_eq!(event.field("db.name"), Some("postgres"));
+        assert_eq!(event.field("db.command_tag"), None);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn test_static_attributes_are_set_on_spans() {
+        use opentelemetry::trace::TracerProvider as _;
+        use opentelemetry::KeyValue;
+        use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+        use opentelemetry_sdk::trace::TracerProvider;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let config = TracingConfig::default()
+            .with_attribute("db.cluster", "eu-west-1")
+            .with_attribute("peer.service", "billing-db");
+        let span = tracing::debug_span!("load", db.name = field::Empty);
+        let mut fields = SpanFields::default();
+        fields.push("db.name", "postgres");
+        fields.record(&span, &config);
+        drop(span);
+
+        let spans = exporter.get_finished_spans().expect("no finished spans");
+        let attributes = &spans[0].attributes;
+        assert!(attributes.contains(&KeyValue::new("db.cluster", "eu-west-1")));
+        assert!(attributes.contains(&KeyValue::new("peer.service", "billing-db")));
+        assert!(attributes.contains(&KeyValue::new("db.name", "postgres")));
+    }
+
+    #[test]
+    fn test_error_event_records_database_error_details() {
+        use diesel::result::{DatabaseErrorInformation, DatabaseErrorKind};
+
+        struct Violation;
+
+        impl DatabaseErrorInformation for Violation {
+            fn message(&self) -> &str {
+                "duplicate key value violates unique constraint \"users_email_key\""
+            }
+            fn details(&self) -> Option<&str> {
+                Some("Key (email)=(a@example.com) already exists.")
+            }
+            fn hint(&self) -> Option<&str> {
+                None
+            }
+            fn table_name(&self) -> Option<&str> {
+                Some("users")
+            }
+            fn column_name(&self) -> Option<&str> {
+                None
+            }
+            fn constraint_name(&self) -> Option<&str> {
+                Some("users_email_key")
+            }
+            fn statement_position(&self) -> Option<i32> {
+                None
+            }
+        }
+
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let error = Error::DatabaseError(DatabaseErrorKind::UniqueViolation, Box::new(Violation));
+        let mut fields = SpanFields::default();
+        fields.push("db.error.code", "23505");
+        fields.log(&TracingConfig::default(), Some(&error));
+
+        let event = capture.error_event();
+        assert_eq!(event.field("db.response.status_code"), Some("23505"));
+        assert_eq!(event.field("db.constraint"), Some("users_email_key"));
+        assert_eq!(event.field("db.sql.table"), Some("users"));
+        assert_eq!(
+            event.field("db.error.detail"),
+            Some("Key (email)=(a@example.com) already exists.")
+        );
+        assert_eq!(event.field("otel.status_code"), Some("ERROR"));
+        // fields not selected with `with_log_fields` are not mirrored
+        assert_eq!(event.field("db.error.code"), None);
+    }
+
+    #[test]
+    fn test_fields_are_named_after_the_conventions() {
+        use crate::config::SemanticConventions;
+
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        for (name, conventions) in [
+            ("stable", SemanticConventions::Stable),
+            ("both", SemanticConventions::Both),
+        ] {
+            let span = tracing::debug_span!(
+                "renamed",
+                otel.name = name,
+                db.name = field::Empty,
+                db.namespace = field::Empty,
+                net.peer.port = field::Empty,
+                server.port = field::Empty,
+                db.version = field::Empty,
+            );
+            let mut fields = SpanFields::default();
+            fields.push("db.name", "postgres");
+            fields.push("net.peer.port", 5432);
+            fields.push("db.version", "12.4");
+            fields.record(
+                &span,
+                &TracingConfig::default().with_semantic_conventions(conventions),
+            );
+        }
+
+        let spans = capture.spans_named("renamed");
+        let (stable, both) = (&spans[0], &spans[1]);
+        assert_eq!(stable.field("db.namespace"), Some("postgres"));
+        assert_eq!(stable.field("server.port"), Some("5432"));
+        assert_eq!(stable.field("db.name"), None);
+        assert_eq!(stable.field("net.peer.port"), None);
+        assert_eq!(stable.field("db.version"), Some("12.4"));
+        assert_eq!(both.field("db.namespace"), Some("postgres"));
+        assert_eq!(both.field("db.name"), Some("postgres"));
+    }
+
+    #[test]
+    fn test_budget_counts_both_names_of_a_field() {
+        use crate::config::SemanticConventions;
+
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let span = tracing::debug_span!(
+            "budgeted",
+            db.name = field::Empty,
+            db.namespace = field::Empty,
+            net.peer.port = field::Empty,
+            server.port = field::Empty,
+            db.version = field::Empty,
+        );
+        let mut fields = SpanFields::default();
+        fields.push("db.version", "12.4");
+        fields.push("net.peer.port", 5432);
+        fields.push("db.name", "postgres");
+        fields.record(
+            &span,
+            &TracingConfig::default()
+                .with_semantic_conventions(SemanticConventions::Both)
+                .with_field_budget(3),
+        );
+
+        let span = capture.span("budgeted");
+        assert_eq!(span.field("db.name"), Some("postgres"));
+        assert_eq!(span.field("db.namespace"), Some("postgres"));
+        assert_eq!(span.field("net.peer.port"), Some("5432"));
+        // the budget ran out before the stable name of the port
+        assert_eq!(span.field("server.port"), None);
+        assert_eq!(span.field("db.version"), None);
+    }
+}
@@ -0,0 +1,411 @@
+//! Statement fingerprints that group statements the same way as the
+//! `pg_stat_statements` extension.
+//!
+//! `pg_stat_statements` groups statements that differ only in their constants,
+//! replacing each constant with a parameter when displaying the statement,
+//! and since version 18 of the server it also squashes lists of constants so
+//! that `IN (1, 2, 3)` and `IN (4, 5)` are grouped together. The statements
+//! are normalized the same way here, ignoring whitespace, comments and the
+//! case of unquoted words, which the server does not distinguish either, and
+//! the fingerprint is a hash of the result.
+
+use std::fmt::Write;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token<'a> {
+    // an unquoted word, lowercased
+    Word(String),
+    // a quoted identifier or punctuation, kept as written
+    Verbatim(&'a str),
+    Const,
+    // a list of constants squashed into its first element
+    Squashed,
+    Param(u32),
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Returns the index just past the end of the quoted string starting at
+/// `start` with the quote `quote`, where doubled quotes are escapes.
+fn end_of_quoted(sql: &str, start: usize, quote: char, backslash_escapes: bool) -> usize {
+    let mut chars = sql[start + 1..].char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        if backslash_escapes && c == '\\' {
+            chars.next();
+        } else if c == quote {
+            if chars.peek().map(|(_, c)| *c) == Some(quote) {
+                chars.next();
+            } else {
+                return start + 1 + index + 1;
+            }
+        }
+    }
+    sql.len()
+}
+
+/// Returns the index just past the end of the `/* */` comment starting at
+/// `start`, allowing for nested comments as the server does.
+fn end_of_comment(sql: &str, start: usize) -> usize {
+    let mut depth = 0;
+    let mut index = start;
+    while index < sql.len() {
+        if sql[index..].starts_with("/*") {
+            depth += 1;
+            index += 2;
+        } else if sql[index..].starts_with("*/") {
+            depth -= 1;
+            index += 2;
+            if depth == 0 {
+                return index;
+            }
+        } else {
+            index += sql[index..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    sql.len()
+}
+
+const OPERATOR: &str = "+-*/<>=~!@#%^&|`?";
+
+/// The length of the operator at the start of `sql`.
+///
+/// Like the server, trailing `+` and `-` are not part of an operator unless
+/// it contains one of ``~!@#%^&|`?``, so that `=-1` is `=` and `-1`.
+fn operator_len(sql: &str) -> usize {
+    let operator = &sql[..sql.find(|c| !OPERATOR.contains(c)).unwrap_or(sql.len())];
+    if operator.contains(|c| "~!@#%^&|`?".contains(c)) {
+        return operator.len();
+    }
+    operator.trim_end_matches(['+', '-']).len().max(1)
+}
+
+/// Whether a `-` following `previous` is a sign rather than a subtraction.
+fn is_sign(previous: Option<&Token<'_>>) -> bool {
+    match previous {
+        None => true,
+        Some(Token::Verbatim(punct)) => !matches!(*punct, ")" | "]") && !punct.starts_with('"'),
+        Some(Token::Word(word)) => matches!(
+            word.as_str(),
+            "select"
+                | "where"
+                | "and"
+                | "or"
+                | "not"
+                | "when"
+                | "then"
+                | "else"
+                | "by"
+                | "limit"
+                | "offset"
+                | "values"
+                | "between"
+                | "in"
+                | "like"
+                | "set"
+        ),
+        Some(_) => false,
+    }
+}
+
+fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while let Some(c) = sql[index..].chars().next() {
+        let start = index;
+        let rest = &sql[index..];
+
+        if c.is_whitespace() {
+            index += c.len_utf8();
+        } else if rest.starts_with("--") {
+            index = rest.find('\n').map_or(sql.len(), |end| start + end);
+        } else if rest.starts_with("/*") {
+            index = end_of_comment(sql, start);
+        } else if c == '\'' {
+            index = end_of_quoted(sql, start, '\'', false);
+            tokens.push(Token::Const);
+        } else if c == '"' {
+            index = end_of_quoted(sql, start, '"', false);
+            tokens.push(Token::Verbatim(&sql[start..index]));
+        } else if c == '$' && rest[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            let digits = rest[1..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len() - 1);
+            index = start + 1 + digits;
+            tokens.push(Token::Param(rest[1..=digits].parse().unwrap_or(0)));
+        } else if c == '$' {
+            // a dollar quoted string, $tag$...$tag$
+            let tag = rest[1..]
+                .find('$')
+                .map(|end| &rest[..end + 2])
+                .filter(|tag| tag[1..tag.len() - 1].chars().all(is_word));
+            index = match tag {
+                Some(tag) => rest[tag.len()..]
+                    .find(tag)
+                    .map_or(sql.len(), |end| start + tag.len() + end + tag.len()),
+                None => start + 1,
+            };
+            tokens.push(match tag {
+                Some(_) => Token::Const,
+                None => Token::Verbatim("$"),
+            });
+        } else if c.is_ascii_digit()
+            || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+        {
+            let mut previous = c;
+            let end = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    let exponent = matches!(previous, 'e' | 'E') && matches!(c, '+' | '-');
+                    previous = c;
+                    !(c.is_ascii_alphanumeric() || c == '.' || c == '_' || exponent)
+                })
+                .map_or(rest.len(), |(end, _)| end);
+            index = start + end;
+            if tokens.last() == Some(&Token::Verbatim("-")) && is_sign(tokens.iter().rev().nth(1)) {
+                tokens.pop();
+            }
+            tokens.push(Token::Const);
+        } else if is_word(c) {
+            let end = rest.find(|c: char| !is_word(c)).unwrap_or(rest.len());
+            let word = rest[..end].to_lowercase();
+            index = start + end;
+            // prefixed strings: E'...', B'...', X'...', N'...' and U&'...'
+            let prefixed = match word.as_str() {
+                "e" | "b" | "x" | "n" if sql[index..].starts_with('\'') => Some(word == "e"),
+                "u" if sql[index..].starts_with("&'") => {
+                    index += 1;
+                    Some(false)
+                }
+                _ => None,
+            };
+            match prefixed {
+                Some(backslash_escapes) => {
+                    index = end_of_quoted(sql, index, '\'', backslash_escapes);
+                    tokens.push(Token::Const);
+                }
+                None if word == "true" || word == "false" => tokens.push(Token::Const),
+                None => tokens.push(Token::Word(word)),
+            }
+        } else if OPERATOR.contains(c) {
+            let end = operator_len(rest);
+            index = start + end;
+            tokens.push(Token::Verbatim(&rest[..end]));
+        } else if rest.starts_with("::") {
+            index += 2;
+            tokens.push(Token::Verbatim("::"));
+        } else {
+            index += c.len_utf8();
+            tokens.push(Token::Verbatim(&rest[..c.len_utf8()]));
+        }
+    }
+
+    tokens
+}
+
+/// Squashes lists of two or more constants following `IN` or `ARRAY` into
+/// their first element, as the server does.
+fn squash_lists<'a>(tokens: &[Token<'a>]) -> Vec<Token<'a>> {
+    let mut squashed: Vec<Token<'_>> = Vec::with_capacity(tokens.len());
+    let mut index = 0;
+
+    while index < tokens.len() {
+        let opens_list = match (squashed.last(), &tokens[index]) {
+            (Some(Token::Word(word)), Token::Verbatim("(")) => word == "in",
+            (Some(Token::Word(word)), Token::Verbatim("[")) => word == "array",
+            _ => false,
+        };
+        let close = match tokens[index] {
+            Token::Verbatim("(") => Token::Verbatim(")"),
+            _ => Token::Verbatim("]"),
+        };
+
+        if opens_list {
+            let elements = tokens[index + 1..]
+                .chunks(2)
+                .take_while(|pair| {
+                    pair[0] == Token::Const && pair.get(1) == Some(&Token::Verbatim(","))
+                })
+                .count();
+            let last = index + 1 + elements * 2;
+            if elements >= 1
+                && tokens.get(last) == Some(&Token::Const)
+                && tokens.get(last + 1) == Some(&close)
+            {
+                squashed.extend([tokens[index].clone(), Token::Const, Token::Squashed, close]);
+                index = last + 2;
+                continue;
+            }
+        }
+
+        squashed.push(tokens[index].clone());
+        index += 1;
+    }
+
+    squashed
+}
+
+/// The unquoted words of `sql`, lowercased, leaving out those in strings and
+/// comments.
+pub(crate) fn words(sql: &str) -> Vec<String> {
+    tokenize(sql)
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Word(word) => Some(word),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The number of parameters `sql` takes, going by the highest numbered one.
+pub(crate) fn placeholders(sql: &str) -> u32 {
+    tokenize(sql)
+        .iter()
+        .filter_map(|token| match token {
+            Token::Param(n) => Some(*n),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// The statements of `sql`, split at the semicolons outside of strings,
+/// quoted identifiers and comments, leaving out those with nothing but
+/// whitespace and comments in them.
+pub(crate) fn split(sql: &str) -> Vec<&str> {
+    // punctuation tokens are slices of `sql`, which gives their offsets
+    let semicolons = tokenize(sql).into_iter().filter_map(|token| match token {
+        Token::Verbatim(text) if text == ";" => {
+            Some(text.as_ptr() as usize - sql.as_ptr() as usize)
+        }
+        _ => None,
+    });
+
+    let mut statements = Vec::new();
+    let mut start = 0;
+    for end in semicolons.chain(std::iter::once(sql.len())) {
+        let statement = &sql[start..end];
+        if !tokenize(statement).is_empty() {
+            statements.push(statement.trim());
+        }
+        start = (end + 1).min(sql.len());
+    }
+    statements
+}
+
+/// Normalizes `sql` as `pg_stat_statements` would, with every constant
+/// replaced by a parameter numbered after the statement's own parameters.
+pub(crate) fn normalize(sql: &str) -> String {
+    let tokens = squash_lists(&tokenize(sql));
+    let mut next_param = placeholders(sql);
+
+    let mut normalized = String::with_capacity(sql.len());
+    for token in tokens {
+        if !normalized.is_empty() {
+            normalized.push(' ');
+        }
+        match token {
+            Token::Word(word) => normalized.push_str(&word),
+            Token::Verbatim(text) => normalized.push_str(text),
+            Token::Const => {
+                next_param += 1;
+                let _ = write!(normalized, "${}", next_param);
+            }
+            Token::Squashed => normalized.push_str("/*, ... */"),
+            Token::Param(n) => {
+                let _ = write!(normalized, "${}", n);
+            }
+        }
+    }
+    normalized
+}
+
+/// The fingerprint of `sql`: the 64-bit FNV-1a hash of its normalized form,
+/// as 16 hexadecimal digits.
+pub(crate) fn fingerprint(sql: &str) -> String {
+    let hash = normalize(sql)
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constants_are_replaced() {
+        assert_eq!(
+            normalize("SELECT * FROM users WHERE id = 42 AND name = 'bob'"),
+            "select * from users where id = $1 and name = $2"
+        );
+        assert_eq!(
+            normalize("UPDATE t SET x = -1.5e-3 WHERE y = $1 -- binds: [3]"),
+            "update t set x = $2 where y = $1"
+        );
+        assert_eq!(normalize("SELECT a - 1 FROM t"), "select a - $1 from t");
+    }
+
+    #[test]
+    fn test_placeholders_ignore_strings_and_comments() {
+        assert_eq!(
+            placeholders("SELECT $2, '$3' FROM t WHERE a = $1 -- binds: [$4]"),
+            2
+        );
+        assert_eq!(placeholders("SELECT 1"), 0);
+    }
+
+    #[test]
+    fn test_fingerprint_groups_like_pg_stat_statements() {
+        let group = fingerprint;
+
+        // lists of constants are squashed regardless of their length
+        assert_eq!(
+            group("SELECT * FROM t WHERE id IN (1, 2, 3)"),
+            group("select * from t where id in (4,5)")
+        );
+        assert_eq!(group("SELECT ARRAY[1, 2, 3]"), group("SELECT ARRAY[4, 5]"));
+        // whitespace, comments and keyword case are insignificant
+        assert_eq!(
+            group("SELECT x FROM t /* report */ WHERE y = 'a'"),
+            group("select x\n  from t where y = E'b'")
+        );
+        // a single element is an equality rather than a list
+        assert_ne!(
+            group("SELECT * FROM t WHERE id IN (1)"),
+            group("SELECT * FROM t WHERE id IN (1, 2)")
+        );
+        // lists containing anything but constants are not squashed
+        assert_ne!(
+            group("SELECT * FROM t WHERE id IN (1, a)"),
+            group("SELECT * FROM t WHERE id IN (1, 2)")
+        );
+        // quoted identifiers keep their case
+        assert_ne!(group(r#"SELECT "Id" FROM t"#), group("SELECT id FROM t"));
+    }
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(
+            split(
+                "CREATE TABLE t (s text DEFAULT 'a;b');\n\
+                 -- a comment; with a semicolon\n\
+                 INSERT INTO \"odd;name\" VALUES (E'\\';');\n\
+                 CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql;\n\
+                 /* trailing; */"
+            ),
+            [
+                "CREATE TABLE t (s text DEFAULT 'a;b')",
+                "-- a comment; with a semicolon\n\
+                 INSERT INTO \"odd;name\" VALUES (E'\\';')",
+                "CREATE FUNCTION f() RETURNS int AS $$ SELECT 1; $$ LANGUAGE sql",
+            ]
+        );
+        assert_eq!(split("SELECT 1"), ["SELECT 1"]);
+        assert!(split(" ; -- nothing\n").is_empty());
+    }
+}
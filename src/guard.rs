@@ -0,0 +1,31 @@
+//! Keeps failures of optional instrumentation from failing the operations
+//! being instrumented.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use tracing::warn;
+
+/// Runs `f`, the optional instrumentation described by `what`, returning
+/// `None` and logging a warning instead if it panics.
+///
+/// Instrumentation only reads from what it is given and builds its own
+/// output, which is dropped on a panic, so it is safe to carry on after one.
+/// The panic hook still runs, so the panic is reported as usual, and nothing
+/// can be done when panics abort.
+pub(crate) fn guarded<R>(what: &'static str, f: impl FnOnce() -> R) -> Option<R> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(output) => Some(output),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            warn!(
+                instrumentation = what,
+                panic = message,
+                "instrumentation panicked, skipping it",
+            );
+            None
+        }
+    }
+}
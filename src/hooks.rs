@@ -0,0 +1,100 @@
+/*!
+Callbacks run around every query of an instrumented connection, set with
+`TracingConfig::with_query_start_hook` and
+`TracingConfig::with_query_end_hook`, for accounting, rate limiting or audit
+logging that the spans of the queries are not enough for.
+
+```no_run
+# #[cfg(feature = "postgres")]
+# fn establish() -> diesel::ConnectionResult<()> {
+use diesel_tracing::pg::InstrumentedPgConnection;
+use diesel_tracing::TracingConfig;
+
+let config = TracingConfig::default().with_query_end_hook(|query| {
+    if query.error.is_some() {
+        eprintln!("{:?} failed after {:?}", query.statement, query.duration);
+    }
+});
+let conn = InstrumentedPgConnection::establish_with_config("postgres://", config)?;
+# Ok(())
+# }
+```
+
+The hooks run on the thread running the query, within its span, and a hook
+that panics is skipped with a warning rather than failing the query.
+*/
+use crate::config::{Hook, TracingConfig};
+use crate::fields::SpanFields;
+use crate::guard;
+use diesel::result::Error;
+use std::time::Duration;
+
+/// A query about to be run, as given to the query start hook.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct QueryStart<'a> {
+    /// The `db.system` of the connection, such as `postgresql`.
+    pub system: &'static str,
+    /// The operation the query performs, such as `SELECT`, where it is known.
+    pub operation: Option<&'a str>,
+    /// The statement of the query, where the statement policy records it.
+    pub statement: Option<&'a str>,
+}
+
+/// A query that has finished, as given to the query end hook.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct QueryEnd<'a> {
+    /// The `db.system` of the connection, such as `postgresql`.
+    pub system: &'static str,
+    /// The operation the query performed, such as `SELECT`, where it is
+    /// known.
+    pub operation: Option<&'a str>,
+    /// The statement of the query, where the statement policy records it.
+    pub statement: Option<&'a str>,
+    /// The name of the query's span, as recorded in `otel.name`, if it has
+    /// one.
+    pub name: Option<&'a str>,
+    /// How long the query took.
+    pub duration: Duration,
+    /// The number of rows the query returned or affected, where it is known.
+    pub rows: Option<usize>,
+    /// The error the query failed with, if it failed.
+    pub error: Option<&'a Error>,
+}
+
+/// Runs the query start hook of `config`, if any, for a query of `system`
+/// described by `fields`.
+pub(crate) fn start(config: &TracingConfig, system: &'static str, fields: &SpanFields<'_>) {
+    if let Some(Hook(hook)) = &config.query_start_hook {
+        let query = QueryStart {
+            system,
+            operation: fields.str("db.operation"),
+            statement: fields.str("db.statement"),
+        };
+        guard::guarded("query start hook", || hook(&query));
+    }
+}
+
+/// Runs the query end hook of `config`, if any, for a query of `system`
+/// described by `fields` that took `duration`.
+pub(crate) fn end(
+    config: &TracingConfig,
+    system: &'static str,
+    fields: &SpanFields<'_>,
+    duration: Duration,
+    error: Option<&Error>,
+) {
+    if let Some(Hook(hook)) = &config.query_end_hook {
+        let query = QueryEnd {
+            system,
+            operation: fields.str("db.operation"),
+            statement: fields.str("db.statement"),
+            name: fields.str("otel.name"),
+            duration,
+            rows: fields.usize("db.response.rows"),
+            error,
+        };
+        guard::guarded("query end hook", || hook(&query));
+    }
+}
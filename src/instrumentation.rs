@@ -0,0 +1,240 @@
+/*!
+Tracing through diesel's own instrumentation hook.
+
+Where a connection cannot be replaced with an instrumented one, an
+[`OpenTelemetryInstrumentation`] can be attached to it instead with
+`Connection::set_instrumentation`, producing spans from the events diesel
+reports. Diesel reports less than the wrappers can see, so these spans only
+carry the fields common to every backend and, if configured, the statement.
+
+```no_run
+# #[cfg(feature = "postgres")]
+# fn run() {
+use diesel::{Connection, PgConnection};
+use diesel_tracing::instrumentation::OpenTelemetryInstrumentation;
+use diesel_tracing::TracingConfig;
+
+let mut conn = PgConnection::establish("postgres://localhost").unwrap();
+conn.set_instrumentation(OpenTelemetryInstrumentation::for_backend::<diesel::pg::Pg>(
+    TracingConfig::default(),
+));
+# }
+```
+*/
+use crate::config::TracingConfig;
+use crate::connection::db_system;
+use crate::fields::SpanFields;
+use crate::guard;
+#[cfg(feature = "metrics")]
+use crate::meter;
+use crate::slow;
+use crate::summary;
+use diesel::backend::Backend;
+use diesel::connection::{Instrumentation, InstrumentationEvent};
+use std::time::Instant;
+use tracing::{debug, error, field, Span};
+
+/// Produces spans from the events diesel reports to a connection's
+/// instrumentation.
+///
+/// Queries get a `query` span and transactions a `transaction` span, which
+/// the spans of the queries run in them nest under. Connections only report
+/// being established to the instrumentation they were established with, so
+/// `establish` spans are only produced when this is installed as diesel's
+/// default instrumentation.
+pub struct OpenTelemetryInstrumentation {
+    system: &'static str,
+    config: TracingConfig,
+    establish: Option<Span>,
+    // the spans of the open transactions and when they began, innermost last
+    transactions: Vec<(Span, Instant)>,
+    // the span of the running query, when it started and its statement
+    query: Option<(Span, Instant, Option<String>)>,
+}
+
+impl OpenTelemetryInstrumentation {
+    /// Instruments connections of the backend `B`, recording telemetry as
+    /// described by `config`.
+    ///
+    /// Only the field budget, the fields mirrored into logs, the correlation
+    /// id, the statement policy, the span levels and the slow query threshold
+    /// apply to this instrumentation.
+    #[must_use]
+    pub fn for_backend<B: Backend>(config: TracingConfig) -> Self {
+        OpenTelemetryInstrumentation {
+            system: db_system::<B>(),
+            config,
+            establish: None,
+            transactions: Vec::new(),
+            query: None,
+        }
+    }
+
+    /// The span new spans are created in: the innermost open transaction, if
+    /// any, and the current span otherwise.
+    fn parent(&self) -> Option<tracing::Id> {
+        match self.transactions.last() {
+            Some((transaction, _)) => transaction.id(),
+            None => Span::current().id(),
+        }
+    }
+
+    /// Closes the innermost transaction, which ended with `outcome`, and
+    /// returns its span.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    fn end_transaction(&mut self, outcome: &'static str) -> Option<Span> {
+        let (span, began) = self.transactions.pop()?;
+        span.record("db.transaction.outcome", outcome);
+        #[cfg(feature = "metrics")]
+        meter::transaction(self.system, None, began.elapsed(), outcome);
+        Some(span)
+    }
+
+    fn record_fields(&self, span: &Span, mut fields: SpanFields<'static>) -> SpanFields<'static> {
+        if let Some(id) = self.config.correlation_id() {
+            fields.push("correlation_id", id);
+        }
+        fields.record(span, &self.config);
+        fields
+    }
+}
+
+impl Instrumentation for OpenTelemetryInstrumentation {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartEstablishConnection { .. } => {
+                let span = span_at!(
+                    self.config.establish_span_level,
+                    parent: self.parent(),
+                    "establish",
+                    db.system = self.system,
+                    otel.kind = "client",
+                    otel.status_code = field::Empty,
+                    correlation_id = field::Empty,
+                );
+                self.record_fields(&span, SpanFields::default());
+                self.establish = Some(span);
+            }
+            InstrumentationEvent::FinishEstablishConnection { error, .. } => {
+                if let (Some(span), Some(e)) = (self.establish.take(), error) {
+                    span.record("otel.status_code", "ERROR");
+                    error!(parent: &span, error = %e, "failed to establish connection");
+                }
+            }
+            InstrumentationEvent::StartQuery { query, .. } => {
+                let span = span_at!(
+                    self.config.query_span_level,
+                    parent: self.parent(),
+                    "query",
+                    db.system = self.system,
+                    otel.kind = "client",
+                    otel.status_code = field::Empty,
+                    correlation_id = field::Empty,
+                    db.statement = field::Empty,
+                    db.query.text = field::Empty,
+                );
+                let statement = if self.config.renders_statements() {
+                    guard::guarded("statement rendering", || query.to_string())
+                        .and_then(|query| self.config.statement_policy.apply(&query))
+                } else {
+                    None
+                };
+                let mut fields = SpanFields::default();
+                if let Some(statement) = &statement {
+                    fields.push("db.statement", statement.clone());
+                }
+                self.record_fields(&span, fields);
+                self.query = Some((span, Instant::now(), statement));
+            }
+            InstrumentationEvent::FinishQuery { error, .. } => {
+                if let Some((span, start, statement)) = self.query.take() {
+                    let elapsed = start.elapsed();
+                    summary::record(elapsed, error.is_some());
+                    #[cfg(feature = "metrics")]
+                    meter::query(self.system, None, &span, elapsed, error.is_some());
+                    let mut fields = SpanFields::default();
+                    if error.is_some() {
+                        fields.push("otel.status_code", "ERROR");
+                    }
+                    let _entered = span.enter();
+                    slow::check(&self.config, elapsed, statement);
+                    self.record_fields(&span, fields).log(&self.config, error);
+                }
+            }
+            InstrumentationEvent::BeginTransaction { depth, .. } => {
+                let span = span_at!(
+                    self.config.query_span_level,
+                    parent: self.parent(),
+                    "transaction",
+                    db.system = self.system,
+                    otel.kind = "client",
+                    correlation_id = field::Empty,
+                    db.transaction.depth = depth.get(),
+                    db.transaction.outcome = field::Empty,
+                );
+                self.record_fields(&span, SpanFields::default());
+                debug!(parent: &span, db.transaction.depth = depth.get(), "transaction begin");
+                self.transactions.push((span, Instant::now()));
+            }
+            InstrumentationEvent::CommitTransaction { depth, .. } => {
+                if let Some(span) = self.end_transaction("commit") {
+                    debug!(parent: &span, db.transaction.depth = depth.get(), "transaction commit");
+                }
+            }
+            InstrumentationEvent::RollbackTransaction { depth, .. } => {
+                if let Some(span) = self.end_transaction("rollback") {
+                    debug!(
+                        parent: &span,
+                        db.transaction.depth = depth.get(),
+                        "transaction rollback"
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+    use crate::StatementPolicy;
+    use diesel::connection::SimpleConnection;
+    use diesel::result::Error;
+    use diesel::{Connection, PgConnection};
+
+    #[test]
+    fn test_vanilla_connection_is_traced() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let mut conn = PgConnection::establish(&url).expect("failed to establish connection");
+        conn.set_instrumentation(OpenTelemetryInstrumentation::for_backend::<diesel::pg::Pg>(
+            TracingConfig::default().with_statement_policy(StatementPolicy::Full),
+        ));
+
+        conn.transaction::<_, Error, _>(|conn| conn.batch_execute("SELECT 1"))
+            .expect("failed to run transaction");
+
+        let query = capture
+            .spans_named("query")
+            .into_iter()
+            .find(|span| span.field("db.statement") == Some("SELECT 1"))
+            .expect("no span for the query");
+        assert_eq!(query.field("db.system"), Some("postgresql"));
+        assert_eq!(query.parent, Some("transaction"));
+        assert_eq!(
+            capture.span("transaction").field("db.transaction.outcome"),
+            Some("commit")
+        );
+        capture.event("transaction commit");
+
+        assert!(conn.batch_execute("SELECT * FROM no_such_table").is_err());
+        assert_eq!(
+            capture.span("query").field("otel.status_code"),
+            Some("ERROR")
+        );
+    }
+}
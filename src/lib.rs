@@ -21,6 +21,19 @@ encapsulated by the `diesel::Connection` trait, so in those places it is
 likely that you will just need to replace your connection type with the
 Instrumented version.
 
+Connections of other backends can be wrapped in the generic
+`InstrumentedConnection`, which records the fields common to every backend,
+such as `db.system` and whether the operation failed, without the backend
+specific ones like the database name. Where the connection type cannot be
+changed at all, `instrumentation::OpenTelemetryInstrumentation` can be
+attached to a plain diesel connection with `Connection::set_instrumentation`,
+producing spans from the events diesel reports.
+
+The instrumented connections can also be the variants of an enum deriving
+`diesel::MultiConnection`, for applications choosing their backend at runtime,
+or be boxed as a `dyn BoxableConnection` of their backend, from which
+`downcast_ref` gets them back.
+
 # Usage
 
 Just like diesel this crate relies on some feature flags to specify which
@@ -32,6 +45,25 @@ database driver to support. Just as in diesel configure this in your
 diesel-tracing = { version = "<version>", features = ["<postgres|mysql|sqlite>"] }
 ```
 
+Connections are configured with a `TracingConfig`, given to
+`establish_with_config`. Those established with `Connection::establish`, as
+pools and frameworks do, use the global configuration, set once at startup
+with `TracingConfig::set_global`, or the default configuration until it is
+set. `TracingConfig::with_env` overrides the statement policy, the slow query
+threshold, the span level and the sampling of spans with the
+`DIESEL_TRACING_STATEMENTS`, `DIESEL_TRACING_SLOW_QUERY_MS`,
+`DIESEL_TRACING_SPAN_LEVEL` and `DIESEL_TRACING_SAMPLING` environment
+variables, so that they can differ between environments:
+
+```no_run
+use diesel_tracing::TracingConfig;
+
+TracingConfig::default()
+    .with_env()
+    .set_global()
+    .expect("the global configuration is set once");
+```
+
 # Notes
 
 ## Fields
@@ -42,34 +74,299 @@ This was chosen for compatibility with the `tracing-opentelemetry` crate, but
 if it makes sense for other standards to be available this could be set by
 feature flag later.
 
-It would be quite useful to be able to parse connection strings to be able
-to provide more information, but this may be difficult if it requires use of
-diesel feature flags by default to access the underlying C bindings.
+The spans of `InstrumentedPgConnection` record the operation a statement
+performs as `db.operation` and the table it operates on as `db.sql.table`,
+taken from the type of a diesel query or parsed from the statement, and name
+themselves from these with `otel.name`, such as `SELECT mydb.users`, so that
+traces group by what a statement does rather than by the diesel method that
+ran it. A query can also be given a name of its own with
+`TracedDsl::traced`, as in `users.filter(active).traced("load_active_users")`,
+which the span of the query records as `otel.name` and `db.operation.name` on
+every synchronous connection, leaving dashboards readable without capturing
+statements. Inserts of a slice or `Vec` of records record the number of
+records as `db.operation.batch.size`, for telling oversized batches apart.
+
+Newer versions of the conventions renamed several of these fields, such as
+`db.name` to `db.namespace`, `net.peer.ip` to `server.address` and
+`db.statement` to `db.query.text`. `TracingConfig::with_semantic_conventions`
+chooses whether spans record the older names, the stable ones or both, which
+helps while moving a backend from one to the other.
+
+Postgres spans record how the connection reaches the server as
+`net.transport`, `ip_tcp` or `unix`, and for TCP whether the server's address
+is IPv4 or IPv6 as `net.sock.family`, `inet` or `inet6`, which the stable
+conventions record as `network.transport` and `network.type`, with the values
+`tcp`, `unix`, `ipv4` and `ipv6`.
+
+Postgres compatible databases that name themselves in their version, such as
+`CockroachDB`, Redshift and `YugabyteDB`, are recorded under their own
+`db.system` once the connection information is known. Others, such as
+`AlloyDB`, can be named with `TracingConfig::with_db_system`.
+
+Postgres connections query the address of the server for `net.peer.ip`
+and `net.sock.family` as an `inet`, which takes the `ipnetwork` crate. It is
+brought in by the `network-address` feature, on by default. Builds leaving it
+out with `default-features = false` take the host and port from the
+connection string instead, and do not record `net.sock.family`.
+
+Whenever the server's address is not known from the connection information,
+as the query is skipped or deferred, left out behind a pooler, over a unix
+domain socket or without the feature, the spans of connections established
+from a connection string record its host, or socket directory, and port as
+`server.address` and `server.port`, without its credentials, so that they
+always say which server they ran against.
 
 ## Levels
 
-All logged traces are currently set to DEBUG level, potentially this could be
-changed to a different default or set to be configured by feature flags. At
-them moment this crate is quite new and it's unclear what a sensible default
-would be.
+Spans are created at DEBUG level by default. The level of the spans of
+operations and of establishing connections can be set independently with
+`TracingConfig::with_query_span_level` and
+`TracingConfig::with_establish_span_level`, so that a service running many
+queries can keep their spans at DEBUG, or TRACE, while recording connections
+being established at INFO.
+
+Where even that is too costly, `TracingConfig::with_query_sampling` records
+the spans of only one in every so many queries of a connection. The queries
+left out that fail still get a span, made once they have failed, so errors
+are always recorded, and the slow queries among them are still logged.
+
+The spans of postgres connections can also be given a target of their own
+with `TracingConfig::with_span_target`, in place of the crate's module path,
+so that the connections to each logical database can be filtered apart, with
+`EnvFilter` directives such as `db.orders=info,db.analytics=off`.
 
 ## Errors
 
-Errors in Result objects returned by methods on the connection should be
-automatically logged through the `err` directive in the `instrument` macro.
+Errors returned by methods on the connection are logged as error events in
+the span of the operation that failed. The spans of operations record
+`otel.status_code` as `OK` or `ERROR`, and the error as
+`otel.status_description`, which `tracing-opentelemetry` sets the status of
+the span from rather than going by the formatting of the error event. With
+the `opentelemetry` feature a failure also emits an `exception` event,
+recording the variant of diesel's error as `exception.type` and its message
+as `exception.message`, which exporters attach to the span. As the error has
+already been logged, it is emitted at DEBUG level with the
+`diesel_tracing::exception` target, which can be enabled for the
+OpenTelemetry layer alone to keep it out of logs. When the
+database reported the error, the error event also records its SQLSTATE as
+`db.response.status_code` where it is known, and the constraint, table and
+detail the database gave as `db.constraint`, `db.sql.table` and
+`db.error.detail`.
+
+## Slow Queries
+
+`TracingConfig::with_slow_query_threshold` logs a `slow query` warning for
+each operation taking longer than the threshold, with the time it took and
+its statement when statements are recorded, so that slow queries can be
+alerted on from logs alone. The operations of async connections are timed
+across their awaits and reported even if they are dropped before completing.
+`TracingConfig::with_slow_query_explain` also plans slow `SELECT` statements on
+postgres connections again, logging their plans in their spans.
+
+## Query Hooks
+
+`TracingConfig::with_query_start_hook` and
+`TracingConfig::with_query_end_hook` register callbacks run before and after
+every query, given its operation and statement where they are known and, once
+it has finished, how long it took, the rows it returned or affected and the
+error it failed with. They suit accounting, rate limiting or audit logging
+that the spans alone do not, without wrapping the connection again.
+
+## Field Budget
+
+Some collectors cap the number of attributes on a span and drop the extras
+without any regard for which ones matter. A field budget can be configured
+with `TracingConfig::with_field_budget` so that optional fields are recorded
+in priority order, with error details first and connection details after,
+and the rest are dropped predictably. The budget counts the attributes
+actually recorded, so a field recorded under both its legacy and its stable
+name with `SemanticConventions::Both` counts twice.
+
+## Transactions
+
+Transactions run through `Connection::transaction` get a span of their own,
+which the spans of the statements run in them nest under, recording whether
+they committed as `db.transaction.outcome`. On `InstrumentedPgConnection`
+nested transactions get a `savepoint` span within the outer transaction's,
+recording `db.transaction.depth` and `db.savepoint.name`, so that partial
+rollbacks show up in traces. Its transaction manager also logs an event as
+each transaction or savepoint begins, commits, is released or rolls back.
+Commits and rollbacks are logged at INFO level with their
+`db.transaction.outcome` and, for rollbacks, why they rolled back as
+`db.transaction.rollback_reason`: `error` when the closure of the
+transaction returned one, or `failed_statement` when a statement failed,
+with its SQLSTATE as `db.error.code`. Log pipelines can count rollbacks from
+these even where the spans of transactions are not recorded.
+Its transaction and savepoint spans also record the statements run in them as
+`db.transaction.statements`, the rows those returned or affected as
+`db.transaction.rows` and the time they took as `db.transaction.db_time_ms`,
+so that a transaction holding its locks far longer than its statements take
+stands out. Rows loaded with `PgRowByRowLoadingMode` are not counted.
+
+`InstrumentedPgConnection::retry_transaction` runs a transaction again when it
+fails on a serialization failure or a deadlock, backing off between attempts
+as its `RetryPolicy` says, with a span for each attempt recording why it was
+retried. `InstrumentedPgConnection::establish_with_retry` does the same for
+connections that fail to be established while the server is out of
+connections, starting up or unreachable, recording the attempts made and how
+long connecting took, which is what a storm of connections at startup needs.
+
+Once a test transaction has begun with `Connection::begin_test_transaction`,
+every span of the connection records `db.test_transaction` as `true`, so
+that the traffic of test suites can be told apart from the rest.
+
+## Connection Poolers
+
+`PgBouncer` in transaction pooling mode does not support the prepared statements
+diesel normally uses and reports its own address from `inet_server_addr()`.
+Declaring the pooler with `TracingConfig::with_proxy` records it as `db.proxy`
+and adapts the connection information query so that establishing still
+works. Where the query adds too much latency or the pooler cannot run it at
+all, `TracingConfig::with_info_query` defers it to the first operation or
+skips it.
+
+The spans of typed queries record whether their statement was found in
+diesel's prepared statement cache as `db.statement_cache.hit`, its key in the
+cache as `db.statement_cache.key` and the size of the cache as
+`db.statement_cache.size`. Queries diesel does not cache, such as
+`sql_query`, are prepared every time they run and always miss, so misses
+without a key point at the source of excessive `PREPARE` traffic.
+
+## Trace Context in Statements
+
+`TracingConfig::with_sql_commenter` appends a SQLCommenter comment to the
+statements of `InstrumentedPgConnection`, tagging them with the application
+and the `traceparent` of the trace running them. The tags show up alongside
+the statements in the server's logs and `pg_stat_activity`, so that what the
+server sees can be traced back to the request responsible. Commented typed
+queries are not cached as prepared statements.
+
+`TracingConfig::with_application_name_from` tags the session instead, setting
+its `application_name` when it is established and for the length of each
+transaction, which is what `pg_stat_activity` shows for a query that hangs.
+
+## Advisory Locks
+
+`InstrumentedPgConnection::advisory_lock`, `try_advisory_lock` and
+`advisory_unlock` take and release session level advisory locks in spans
+recording the key of the lock as `db.advisory_lock.key`, whether it was
+taken as `db.advisory_lock.acquired` and how long taking it waited as
+`db.advisory_lock.wait_ms`, so that contention for the locks is visible.
+
+## Statement Timeouts
+
+`InstrumentedPgConnection::with_statement_timeout` runs a closure with the
+`statement_timeout` of the session set, and set back once it returns, in a
+`statement_timeout` span recording the timeout. A statement the timeout
+cancels logs a `statement timed out` error event with `error.type` set to
+`statement_timeout`, so that timeouts stand out from other failures.
+
+## Notifications
+
+`InstrumentedPgConnection::listen` issues `LISTEN` and opens a long-lived
+`listening` span for as long as the connection listens on any channel. The
+notifications polled with `InstrumentedPgConnection::notifications` are each
+logged in it with their channel, the length of their payload and the process
+id of the backend that sent them.
+
+## Connection Pools
+
+Instrumented connections can be pooled with r2d2 through diesel's
+`ConnectionManager`. Registering a `pool::PoolEvents` handler with the pool
+also traces connections being added to the pool, checkouts timing out and
+why pooled connections are closed, such as reaching the pool's idle timeout or
+maximum lifetime. Pools built as a `pool::InstrumentedPool` trace each
+checkout in a span recording how long it waited and whether it got a newly
+established connection. The pings pools validate connections with get a
+`ping` span of their own, recording their latency as `db.duration_ms`.
+
+## Migrations
+
+The instrumented connections implement diesel's `MigrationConnection`, so
+that `diesel_migrations` can run migrations on them. With the `migrations`
+feature, `migrations::run_pending_migrations` runs the pending migrations
+each in a `migration` span of its own, recording its name, version, duration
+and whether it failed.
+
+## Connection Registry
+
+The `registry` feature keeps track of the instrumented connections alive in
+the process. `registry::snapshot` lists them with their database, server
+address, age and the number of operations run on them, for diagnostic views.
+
+## Metrics
+
+The `metrics` feature also counts and times operations and transactions
+through the `metrics` crate, in `db.client.queries`, `db.client.errors`,
+`db.client.operation.duration` and `db.client.transaction.duration`, with
+labels for `db.system`, `db.name` and the operation. These aggregates are
+exact whatever fraction of traces is sampled and can be exported to
+Prometheus with any recorder for the `metrics` facade.
+
+## Static Attributes
+
+The `opentelemetry` feature adds `TracingConfig::with_attribute`, for tagging
+every span of a connection with deployment specific attributes such as
+`db.cluster`, `service.shard` or `peer.service`. As `tracing` can only record
+fields declared when a span is created, these are set through
+`tracing-opentelemetry` and only show up on the exported `OpenTelemetry`
+spans.
+
+## Async Connections
+
+The `async` feature provides `async_connection::InstrumentedAsyncPgConnection`
+and `async_connection::InstrumentedAsyncMysqlConnection`, wrapping the
+connections of `diesel-async` with the same spans and fields. The span of a
+load covers reading its rows until the end of the stream.
+
+With the `bb8` or `deadpool` feature, `async_connection::pool` pools these
+connections through `diesel-async`'s connection managers, recording the name
+given with `TracingConfig::with_pool_name` as `db.pool.name` on the spans of
+the pool's connections and tracing checkouts in `checkout` spans.
+
+## Testing
+
+The `test-util` feature adds `PgConnectionInfo::new` and
+`InstrumentedPgConnection::with_info`, which replace the connection
+information recorded on spans with made up values so that tests can assert on
+those fields regardless of the server they run against.
+
+It also adds the `test` module, whose `Capture` layer keeps the spans and
+events recorded while it is installed, with their fields, their parents and
+whether they failed, so that tests can assert on what their database
+operations recorded without setting up a subscriber of their own.
+
+## Opting Out
+
+The `noop` feature turns `InstrumentedPgConnection` into a passthrough: its
+operations run straight on the inner connection, no spans or events are
+recorded and no connection information is queried at establish. Libraries
+can name the instrumented type unconditionally while a binary, say one being
+benchmarked, opts out of the instrumentation by enabling the feature. As
+cargo unifies features, enabling it anywhere in a build turns off the
+instrumentation of every postgres connection in it.
 
 ## Sensitive Information
 
-As statements may contain sensitive information they are currently not recorded
-explicitly, pending finding a way to filter things intelligently.
+As statements may contain sensitive information they are not recorded unless
+enabled with `TracingConfig::with_statement_policy`, which records them as
+`db.statement`. `StatementPolicy::Full` records typed queries rendered with
+their bind values by `diesel::debug_query`, while `StatementPolicy::Sanitized`
+strips literals and binds from statements before they are recorded.
 
-Similarly connection strings are not recorded in spans as they may contain
-passwords
+Similarly connection strings are not recorded in spans as they are, as they
+may contain passwords. The `establish` span of postgres connections records
+them as `db.connection_string` with only their scheme, host, port and
+database, as in `postgres://db.internal:6432/orders`, which is enough to tell
+which of several databases a service connected to.
 
-## TODO
+## Server Notices
 
-- [ ] Record and log connection information (filtering out sensitive fields)
-- [ ] Provide a way of filtering statements, maybe based on regex?
+`NOTICE` and `WARNING` messages sent by the server, such as those raised by
+`DO` blocks, are not recorded. diesel installs a notice processor on each
+libpq connection that discards them, and `PgConnection` offers no way of
+replacing it, so they are lost on the client. To keep them, have the server
+log them instead by lowering `log_min_messages` to `notice`.
 
 */
 #![warn(clippy::all, clippy::pedantic)]
@@ -77,9 +374,46 @@ passwords
 #[macro_use]
 extern crate diesel;
 
+#[macro_use]
+mod callsite;
+#[macro_use]
+mod config;
+pub mod connection;
+mod fields;
+mod fingerprint;
+mod guard;
+mod id;
+pub mod instrumentation;
+#[cfg(feature = "metrics")]
+mod meter;
+#[cfg(feature = "migrations")]
+pub mod migrations;
+mod query;
+mod sampling;
+mod slow;
+mod statement;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test;
+#[cfg(feature = "postgres")]
+mod url;
+
+#[cfg(feature = "async")]
+pub mod async_connection;
+pub mod hooks;
 #[cfg(feature = "mysql")]
 pub mod mysql;
 #[cfg(feature = "postgres")]
 pub mod pg;
+pub mod pool;
+#[cfg(feature = "registry")]
+pub mod registry;
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
+pub mod summary;
+pub mod traced;
+
+pub use config::{
+    InfoQuery, Proxy, SemanticConventions, SpanLevel, StatementPolicy, TracingConfig,
+};
+pub use connection::InstrumentedConnection;
+pub use traced::{Traced, TracedDsl};
@@ -0,0 +1,122 @@
+/*!
+Metrics recorded alongside spans, through the `metrics` crate.
+
+With the `metrics` feature enabled, every operation and transaction run on an
+instrumented connection is counted and timed with the recorder installed for
+the `metrics` facade, so that aggregates such as query rates and latency
+percentiles are available without sampling traces. The metrics are labelled
+with `db.system`, `db.name` and, for operations, `db.operation`, the name of
+the operation's span.
+
+| Metric | Kind | Labels |
+| --- | --- | --- |
+| `db.client.queries` | counter | `db.system`, `db.name`, `db.operation` |
+| `db.client.errors` | counter | `db.system`, `db.name`, `db.operation` |
+| `db.client.operation.duration` | histogram, in seconds | `db.system`, `db.name`, `db.operation` |
+| `db.client.transaction.duration` | histogram, in seconds | `db.system`, `db.name`, `db.transaction.outcome` |
+*/
+use metrics::Label;
+use std::time::Duration;
+use tracing::Span;
+
+fn labels(system: &'static str, database: Option<&str>) -> Vec<Label> {
+    vec![
+        Label::new("db.system", system),
+        Label::new("db.name", database.unwrap_or_default().to_owned()),
+    ]
+}
+
+/// Counts and times an operation that ran in `span`, which names it.
+pub(crate) fn query(
+    system: &'static str,
+    database: Option<&str>,
+    span: &Span,
+    elapsed: Duration,
+    failed: bool,
+) {
+    let operation = span
+        .metadata()
+        .map_or("unknown", |metadata| metadata.name());
+    let mut labels = labels(system, database);
+    labels.push(Label::new("db.operation", operation));
+
+    metrics::counter!("db.client.queries", labels.clone()).increment(1);
+    if failed {
+        metrics::counter!("db.client.errors", labels.clone()).increment(1);
+    }
+    metrics::histogram!("db.client.operation.duration", labels).record(elapsed.as_secs_f64());
+}
+
+/// Times a transaction that ended with `outcome`.
+pub(crate) fn transaction(
+    system: &'static str,
+    database: Option<&str>,
+    elapsed: Duration,
+    outcome: &'static str,
+) {
+    let mut labels = labels(system, database);
+    labels.push(Label::new("db.transaction.outcome", outcome));
+
+    metrics::histogram!("db.client.transaction.duration", labels).record(elapsed.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use metrics_util::MetricKind;
+
+    #[test]
+    fn test_operations_are_counted_and_timed() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let span = tracing::debug_span!("load");
+        metrics::with_local_recorder(&recorder, || {
+            query(
+                "postgresql",
+                Some("app"),
+                &span,
+                Duration::from_millis(5),
+                false,
+            );
+            query(
+                "postgresql",
+                Some("app"),
+                &span,
+                Duration::from_millis(5),
+                true,
+            );
+            transaction(
+                "postgresql",
+                Some("app"),
+                Duration::from_millis(10),
+                "commit",
+            );
+        });
+
+        let metrics = snapshotter.snapshot().into_vec();
+        let value = |kind, name: &str| {
+            metrics
+                .iter()
+                .find(|(key, ..)| key.kind() == kind && key.key().name() == name)
+                .map(|(key, _, _, value)| (key.key().labels().cloned().collect::<Vec<_>>(), value))
+                .unwrap_or_else(|| panic!("no metric named {}", name))
+        };
+
+        let (labels, queries) = value(MetricKind::Counter, "db.client.queries");
+        assert_eq!(queries, &DebugValue::Counter(2));
+        assert!(labels.contains(&Label::new("db.system", "postgresql")));
+        assert!(labels.contains(&Label::new("db.name", "app")));
+        assert!(labels.contains(&Label::new("db.operation", "load")));
+        assert_eq!(
+            value(MetricKind::Counter, "db.client.errors").1,
+            &DebugValue::Counter(1)
+        );
+        match value(MetricKind::Histogram, "db.client.operation.duration").1 {
+            DebugValue::Histogram(durations) => assert_eq!(durations.len(), 2),
+            other => panic!("unexpected value {:?}", other),
+        }
+        let (labels, _) = value(MetricKind::Histogram, "db.client.transaction.duration");
+        assert!(labels.contains(&Label::new("db.transaction.outcome", "commit")));
+    }
+}
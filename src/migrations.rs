@@ -0,0 +1,200 @@
+/*!
+Spans for migrations run with `diesel_migrations`.
+
+The instrumented connections implement diesel's `MigrationConnection`, so
+`MigrationHarness` can run migrations on them directly, tracing each of the
+statements the migrations run. [`run_pending_migrations`] also gives each
+migration a `migration` span of its own, recording its name, version, how
+long it took and whether it failed, nested in a `run_pending_migrations` span
+recording how many migrations were pending.
+
+```no_run
+use diesel::Connection;
+use diesel_migrations::FileBasedMigrations;
+use diesel_tracing::pg::InstrumentedPgConnection;
+
+let migrations = FileBasedMigrations::find_migrations_directory().unwrap();
+let mut conn = InstrumentedPgConnection::establish("postgres://localhost").unwrap();
+diesel_tracing::migrations::run_pending_migrations(&mut conn, migrations).unwrap();
+```
+
+Migrations usually run once at startup, so unlike the spans of operations
+these are created at `INFO` level.
+*/
+use crate::connection::db_system;
+use diesel::backend::Backend;
+use diesel::migration::{Migration, MigrationSource, MigrationVersion, Result};
+use diesel_migrations::MigrationHarness;
+use std::time::Instant;
+use tracing::{error, field, info_span};
+
+/// Runs the migrations of `source` that have not been run on `conn` yet,
+/// each in a `migration` span, returning the versions of those it ran.
+///
+/// # Errors
+///
+/// Fails if the pending migrations cannot be listed or if one of them fails,
+/// in which case the migrations after it are not run.
+pub fn run_pending_migrations<C, DB, S>(
+    conn: &mut C,
+    source: S,
+) -> Result<Vec<MigrationVersion<'static>>>
+where
+    DB: Backend,
+    C: MigrationHarness<DB>,
+    S: MigrationSource<DB>,
+{
+    let span = info_span!(
+        "run_pending_migrations",
+        db.system = db_system::<DB>(),
+        db.migration.pending = field::Empty,
+        otel.status_code = field::Empty,
+    );
+    let _entered = span.enter();
+
+    let pending = conn.pending_migrations(source).map_err(|e| {
+        error!(error = %e, "failed to list pending migrations");
+        e
+    })?;
+    span.record("db.migration.pending", pending.len());
+
+    let mut applied = Vec::with_capacity(pending.len());
+    for migration in &pending {
+        match run_migration(conn, migration.as_ref()) {
+            Ok(version) => applied.push(version),
+            Err(e) => {
+                span.record("otel.status_code", "ERROR");
+                return Err(e);
+            }
+        }
+    }
+    Ok(applied)
+}
+
+/// Runs `migration` on `conn` in a `migration` span.
+fn run_migration<C, DB>(
+    conn: &mut C,
+    migration: &dyn Migration<DB>,
+) -> Result<MigrationVersion<'static>>
+where
+    DB: Backend,
+    C: MigrationHarness<DB>,
+{
+    let name = migration.name();
+    let span = info_span!(
+        "migration",
+        db.system = db_system::<DB>(),
+        db.migration.name = %name,
+        db.migration.version = %name.version(),
+        db.duration_ms = field::Empty,
+        otel.status_code = field::Empty,
+    );
+    let _entered = span.enter();
+
+    let start = Instant::now();
+    let result = conn.run_migration(migration);
+    span.record(
+        "db.duration_ms",
+        u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX),
+    );
+    if let Err(e) = &result {
+        span.record("otel.status_code", "ERROR");
+        error!(error = %e);
+    }
+    result
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod tests {
+    use super::*;
+    use crate::pg::InstrumentedPgConnection;
+    use crate::test::Capture;
+    use diesel::connection::{BoxableConnection, Connection};
+    use diesel::migration::{MigrationMetadata, MigrationName};
+    use diesel::pg::Pg;
+    use std::fmt;
+
+    struct Name;
+
+    impl fmt::Display for Name {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("20240101000000_create_migration_test")
+        }
+    }
+
+    impl MigrationName for Name {
+        fn version(&self) -> MigrationVersion<'_> {
+            MigrationVersion::from("20240101000000")
+        }
+    }
+
+    struct CreateTable;
+
+    impl MigrationMetadata for CreateTable {}
+
+    impl Migration<Pg> for CreateTable {
+        fn run(&self, conn: &mut dyn BoxableConnection<Pg>) -> Result<()> {
+            conn.batch_execute("CREATE TABLE migration_test (id integer)")?;
+            Ok(())
+        }
+
+        fn revert(&self, conn: &mut dyn BoxableConnection<Pg>) -> Result<()> {
+            conn.batch_execute("DROP TABLE migration_test")?;
+            Ok(())
+        }
+
+        fn metadata(&self) -> &dyn MigrationMetadata {
+            self
+        }
+
+        fn name(&self) -> &dyn MigrationName {
+            &Name
+        }
+    }
+
+    struct Source;
+
+    impl MigrationSource<Pg> for Source {
+        fn migrations(&self) -> Result<Vec<Box<dyn Migration<Pg>>>> {
+            Ok(vec![Box::new(CreateTable)])
+        }
+    }
+
+    #[test]
+    fn test_migrations_get_spans() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedPgConnection::establish(
+            &std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified"),
+        )
+        .expect("failed to establish connection");
+        conn.begin_test_transaction()
+            .expect("failed to begin test transaction");
+
+        let applied = run_pending_migrations(&mut conn, Source).expect("failed to migrate");
+        assert_eq!(applied, [MigrationVersion::from("20240101000000")]);
+
+        let span = capture.span("migration");
+        assert_eq!(span.parent, Some("run_pending_migrations"));
+        assert_eq!(
+            span.field("db.migration.name"),
+            Some("20240101000000_create_migration_test")
+        );
+        assert_eq!(span.field("db.migration.version"), Some("20240101000000"));
+        assert!(span.field("db.duration_ms").is_some());
+        assert_eq!(span.field("otel.status_code"), None);
+        assert_eq!(
+            capture
+                .span("run_pending_migrations")
+                .field("db.migration.pending"),
+            Some("1")
+        );
+
+        // the statements of the migration nest under its span
+        assert!(capture
+            .spans_named("batch_execute")
+            .iter()
+            .any(|span| span.parent == Some("migration")));
+    }
+}
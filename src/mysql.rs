@@ -1,21 +1,141 @@
-use diesel::connection::{AnsiTransactionManager, Connection, SimpleConnection};
-use diesel::deserialize::{Queryable, QueryableByName};
+use crate::config::TracingConfig;
+use crate::connection;
+use crate::fields::SpanFields;
+use crate::fingerprint;
+use crate::guard;
+use crate::hooks;
+#[cfg(feature = "metrics")]
+use crate::meter;
+use crate::query::RowCount;
+use crate::sampling::{QuerySpan, SpanSampler};
+use crate::slow;
+use crate::summary;
+use crate::traced;
+use diesel::connection::{
+    AnsiTransactionManager, BoxableConnection, CacheSize, Connection, Instrumentation,
+    LoadConnection, MultiConnectionHelper, SimpleConnection, TransactionManager,
+};
+use diesel::expression::QueryMetadata;
+use diesel::migration::MigrationConnection;
 use diesel::mysql::{Mysql, MysqlConnection};
-use diesel::query_builder::{AsQuery, QueryFragment, QueryId};
-use diesel::result::{ConnectionResult, QueryResult};
-use diesel::sql_types::HasSqlType;
-use tracing::instrument;
+use diesel::query_builder::{Query, QueryFragment, QueryId};
+use diesel::r2d2::R2D2Connection;
+use diesel::result::{ConnectionError, ConnectionResult, Error, QueryResult};
+use diesel::sql_types::{BigInt, Nullable, Text, TypeMetadata};
+use diesel::{sql_query, RunQueryDsl};
+use std::any::Any;
+use tracing::{debug, error, field, info, Span};
+
+/// Information about the server a connection is established to, gathered at
+/// establish and recorded on the connection's spans.
+// https://dev.mysql.com/doc/refman/8.0/en/information-functions.html
+#[derive(QueryableByName, Clone, Debug, PartialEq)]
+struct MysqlConnectionInfo {
+    // db.name, unless no database was selected
+    #[diesel(sql_type = Nullable<Text>)]
+    current_database: Option<String>,
+    // db.version
+    #[diesel(sql_type = Text)]
+    version: String,
+    // net.peer.name
+    #[diesel(sql_type = Nullable<Text>)]
+    hostname: Option<String>,
+    // net.peer.port
+    #[diesel(sql_type = Nullable<BigInt>)]
+    port: Option<i64>,
+}
+
+impl MysqlConnectionInfo {
+    fn query(conn: &mut MysqlConnection) -> QueryResult<Self> {
+        sql_query(
+            "SELECT DATABASE() AS current_database, VERSION() AS version, \
+             @@hostname AS hostname, CAST(@@port AS SIGNED) AS port",
+        )
+        .get_result(conn)
+    }
+
+    fn record_fields<'a>(&'a self, fields: &mut SpanFields<'a>) {
+        if let Some(database) = &self.current_database {
+            fields.push("db.name", database.as_str());
+        }
+        fields.push("db.version", self.version.as_str());
+        if let Some(hostname) = &self.hostname {
+            fields.push("net.peer.name", hostname.as_str());
+        }
+        if let Some(port) = self.port {
+            fields.push("net.peer.port", port);
+        }
+    }
+}
+
+/// The fingerprint of `query`, rendered as `diesel::debug_query` renders it,
+/// if `TracingConfig::with_query_fingerprint` asks for it.
+fn fingerprint_of<T: QueryFragment<Mysql>>(config: &TracingConfig, query: &T) -> Option<String> {
+    if !config.query_fingerprint {
+        return None;
+    }
+    guard::guarded("statement rendering", || {
+        fingerprint::fingerprint(&diesel::debug_query::<Mysql, _>(query).to_string())
+    })
+}
+
+/// Creates the span for an operation on an established connection, with
+/// every optional field left empty until the operation has finished.
+macro_rules! connection_span {
+    ($level:expr, $name:literal $(, $($field:tt)+)?) => {
+        span_at!(
+            $level,
+            $name,
+            db.name = field::Empty,
+            db.system = "mysql",
+            db.version = field::Empty,
+            otel.kind = "client",
+            net.peer.name = field::Empty,
+            net.peer.port = field::Empty,
+            correlation_id = field::Empty,
+            db.response.rows = field::Empty,
+            db.result = field::Empty,
+            db.query.fingerprint = field::Empty,
+            otel.name = field::Empty,
+            db.operation.name = field::Empty,
+            db.test_transaction = field::Empty,
+            db.transaction.depth = field::Empty,
+            db.transaction.status = field::Empty,
+            db.namespace = field::Empty,
+            otel.status_code = field::Empty,
+            otel.status_description = field::Empty,
+            server.address = field::Empty,
+            server.port = field::Empty,
+            $($($field)+)?
+        )
+    };
+}
 
 pub struct InstrumentedMysqlConnection {
     inner: MysqlConnection,
+    info: MysqlConnectionInfo,
+    config: TracingConfig,
+    span_sampler: SpanSampler,
+    // whether a test transaction has begun, which is never committed
+    test_transaction: bool,
+    #[cfg(feature = "registry")]
+    registration: crate::registry::Registration,
 }
 
 impl SimpleConnection for InstrumentedMysqlConnection {
-    #[instrument(fields(db.system="mysql", otel.kind="client"), skip(self, query), err)]
-    fn batch_execute(&self, query: &str) -> QueryResult<()> {
-        self.inner.batch_execute(query)?;
-
-        Ok(())
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        let level = self.config.query_span_level;
+        let mut span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "batch_execute")
+        });
+        let fingerprint = self
+            .config
+            .query_fingerprint
+            .then(|| fingerprint::fingerprint(query));
+        self.in_span(&mut span, false, fingerprint, |conn| {
+            debug!("executing batch query");
+            conn.batch_execute(query)
+        })
     }
 }
 
@@ -23,53 +143,368 @@ impl Connection for InstrumentedMysqlConnection {
     type Backend = Mysql;
     type TransactionManager = AnsiTransactionManager;
 
-    #[instrument(fields(db.system="mysql", otel.kind="client"), skip(database_url), err)]
     fn establish(database_url: &str) -> ConnectionResult<InstrumentedMysqlConnection> {
-        Ok(InstrumentedMysqlConnection {
-            inner: MysqlConnection::establish(database_url)?,
-        })
+        InstrumentedMysqlConnection::establish_with_config(database_url, TracingConfig::global())
     }
 
     #[doc(hidden)]
-    #[instrument(fields(db.system="mysql", otel.kind="client"), skip(self, query), err)]
-    fn execute(&self, query: &str) -> QueryResult<usize> {
-        self.inner.execute(query)
-    }
-
-    #[doc(hidden)]
-    #[instrument(fields(db.system="mysql", otel.kind="client"), skip(self, source), err)]
-    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    fn execute_returning_count<T>(&mut self, source: &T) -> QueryResult<usize>
     where
-        T: AsQuery,
-        T::Query: QueryFragment<Mysql> + QueryId,
-        Mysql: HasSqlType<T::SqlType>,
-        U: Queryable<T::SqlType, Mysql>,
+        T: QueryFragment<Mysql> + QueryId,
     {
-        self.inner.query_by_index(source)
+        let level = self.config.query_span_level;
+        let mut span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "execute_returning_count")
+        });
+        let fingerprint = fingerprint_of(&self.config, source);
+        self.in_span(&mut span, false, fingerprint, |conn| {
+            debug!("executing returning count");
+            conn.execute_returning_count(source)
+        })
     }
 
-    #[doc(hidden)]
-    #[instrument(fields(db.system="mysql", otel.kind="client"), skip(self, source), err)]
-    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
     where
-        T: QueryFragment<Mysql> + QueryId,
-        U: QueryableByName<Mysql>,
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<Error>,
     {
-        self.inner.query_by_name(source)
+        let span = connection_span!(self.config.query_span_level, "transaction");
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        debug!("running transaction");
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = Self::TransactionManager::transaction(self, f);
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        #[cfg(feature = "metrics")]
+        meter::transaction(
+            "mysql",
+            self.info.current_database.as_deref(),
+            start.elapsed(),
+            if result.is_err() {
+                "rollback"
+            } else {
+                "commit"
+            },
+        );
+        result
+    }
+
+    fn begin_test_transaction(&mut self) -> QueryResult<()> {
+        let mut span = QuerySpan::from(connection_span!(
+            self.config.query_span_level,
+            "begin_test_transaction"
+        ));
+        self.test_transaction = true;
+        self.in_span(&mut span, false, None, |conn| {
+            debug!("beginning test transaction");
+            conn.begin_test_transaction()
+        })
     }
 
     #[doc(hidden)]
-    #[instrument(fields(db.system="mysql", otel.kind="client"), skip(self, source), err)]
-    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    fn transaction_state(
+        &mut self,
+    ) -> &mut <Self::TransactionManager as TransactionManager<Self>>::TransactionStateData {
+        let span = connection_span!(self.config.query_span_level, "transaction_state");
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        debug!("retrieving transaction state");
+        self.inner.transaction_state()
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        self.inner.instrumentation()
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.inner.set_instrumentation(instrumentation);
+    }
+
+    fn set_prepared_statement_cache_size(&mut self, size: CacheSize) {
+        let span = connection_span!(
+            self.config.query_span_level,
+            "set_prepared_statement_cache_size"
+        );
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        info!(
+            db.statement_cache.size = connection::cache_size_name(size),
+            "prepared statement cache size changed",
+        );
+        self.inner.set_prepared_statement_cache_size(size);
+    }
+}
+
+impl LoadConnection for InstrumentedMysqlConnection {
+    // the rows are collected to count them
+    type Cursor<'conn, 'query> = std::vec::IntoIter<QueryResult<Self::Row<'conn, 'query>>>;
+    type Row<'conn, 'query> = <MysqlConnection as LoadConnection>::Row<'conn, 'query>;
+
+    fn load<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> QueryResult<Self::Cursor<'conn, 'query>>
     where
-        T: QueryFragment<Mysql> + QueryId,
+        T: Query + QueryFragment<Mysql> + QueryId + 'query,
+        Mysql: QueryMetadata<T::SqlType>,
     {
-        self.inner.execute_returning_count(source)
+        let level = self.config.query_span_level;
+        let mut span = self
+            .span_sampler
+            .span(&self.config, move || connection_span!(level, "load"));
+        let fingerprint = fingerprint_of(&self.config, &source);
+        self.in_span(&mut span, true, fingerprint, |conn| {
+            debug!("loading rows");
+            Ok(conn.load(source)?.collect::<Vec<_>>())
+        })
+        .map(Vec::into_iter)
     }
+}
 
-    #[doc(hidden)]
-    #[instrument(fields(db.system="mysql", otel.kind="client"), skip(self))]
-    fn transaction_manager(&self) -> &Self::TransactionManager {
-        &self.inner.transaction_manager()
+// lets the connection be a variant of a `#[derive(MultiConnection)]` enum
+impl MultiConnectionHelper for InstrumentedMysqlConnection {
+    fn to_any<'a>(lookup: &mut <Mysql as TypeMetadata>::MetadataLookup) -> &mut (dyn Any + 'a) {
+        <MysqlConnection as MultiConnectionHelper>::to_any(lookup)
+    }
+
+    fn from_any(lookup: &mut dyn Any) -> Option<&mut <Mysql as TypeMetadata>::MetadataLookup> {
+        <MysqlConnection as MultiConnectionHelper>::from_any(lookup)
+    }
+}
+
+impl MigrationConnection for InstrumentedMysqlConnection {
+    fn setup(&mut self) -> QueryResult<usize> {
+        self.inner.setup()
+    }
+}
+
+impl R2D2Connection for InstrumentedMysqlConnection {
+    fn ping(&mut self) -> QueryResult<()> {
+        let mut span = QuerySpan::from(connection_span!(
+            self.config.query_span_level,
+            "ping",
+            db.duration_ms = field::Empty,
+        ));
+        let start = std::time::Instant::now();
+        let result = self.in_span(&mut span, false, None, |conn| {
+            debug!("pinging connection");
+            conn.ping()
+        });
+        span.record("db.duration_ms", start.elapsed().as_secs_f64() * 1000.0);
+        result
+    }
+}
+
+impl InstrumentedMysqlConnection {
+    /// The instrumented connection boxed as `conn`, if it is one, for code
+    /// given connections as `Box<dyn BoxableConnection<Mysql>>`.
+    ///
+    /// Like every connection, the instrumented connection is a
+    /// `BoxableConnection` of its backend, so it can be boxed as one in place
+    /// of the connection it wraps, and its operations are traced when run
+    /// through the box.
+    #[must_use]
+    pub fn downcast_ref(conn: &dyn BoxableConnection<Mysql>) -> Option<&Self> {
+        conn.as_any().downcast_ref()
+    }
+
+    /// The wrapped connection, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced.
+    #[must_use]
+    pub fn as_inner(&self) -> &MysqlConnection {
+        &self.inner
+    }
+
+    /// The wrapped connection, mutably, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced, and transactions should
+    /// not be started or ended through it as the wrapper would not know of
+    /// them.
+    pub fn as_inner_mut(&mut self) -> &mut MysqlConnection {
+        &mut self.inner
+    }
+
+    /// Unwraps the connection, which is no longer traced.
+    #[must_use]
+    pub fn into_inner(self) -> MysqlConnection {
+        self.inner
+    }
+
+    /// Establishes a connection, recording telemetry as described by `config`.
+    ///
+    /// Only the field budget, the fields mirrored into logs, the correlation
+    /// id, the span levels, the slow query threshold, query sampling, query
+    /// fingerprints and the size of the prepared statement cache apply to
+    /// these connections, the other options being specific to the
+    /// `postgres` backend.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the connection cannot be established or if the connection
+    /// information cannot be queried from the server.
+    pub fn establish_with_config(
+        database_url: &str,
+        config: TracingConfig,
+    ) -> ConnectionResult<InstrumentedMysqlConnection> {
+        let span = span_at!(
+            config.establish_span_level,
+            "establish_with_config",
+            db.name = field::Empty,
+            db.system = "mysql",
+            db.version = field::Empty,
+            otel.kind = "client",
+            net.peer.name = field::Empty,
+            net.peer.port = field::Empty,
+            correlation_id = field::Empty,
+            db.namespace = field::Empty,
+            server.address = field::Empty,
+            server.port = field::Empty,
+        );
+        let _entered = span.enter();
+        let result = Self::establish_recorded(database_url, config);
+        if let Err(e) = &result {
+            error!(error = %e);
+        }
+        result
+    }
+
+    /// Establishes a connection, recording what is known about it on the
+    /// current span.
+    fn establish_recorded(
+        database_url: &str,
+        config: TracingConfig,
+    ) -> ConnectionResult<InstrumentedMysqlConnection> {
+        debug!("establishing mysql connection");
+        let mut conn = MysqlConnection::establish(database_url)?;
+        if let Some(size) = config.statement_cache_size {
+            conn.set_prepared_statement_cache_size(size);
+        }
+
+        debug!("querying mysql connection information");
+        let info = MysqlConnectionInfo::query(&mut conn)
+            .map_err(ConnectionError::CouldntSetupConfiguration)?;
+
+        #[cfg(feature = "registry")]
+        let registration = crate::registry::Registration::new(
+            "mysql",
+            info.current_database.clone().unwrap_or_default(),
+            info.hostname.clone(),
+        );
+
+        let conn = InstrumentedMysqlConnection {
+            inner: conn,
+            info,
+            config,
+            span_sampler: SpanSampler::default(),
+            test_transaction: false,
+            #[cfg(feature = "registry")]
+            registration,
+        };
+        conn.record_fields(&Span::current(), SpanFields::default());
+
+        Ok(conn)
+    }
+
+    /// Runs `f` against the inner connection inside `span`, recording the
+    /// connection fields, the fingerprint of the statement and the outcome
+    /// once it has returned.
+    fn in_span<R: RowCount>(
+        &mut self,
+        span: &mut QuerySpan<'_>,
+        read: bool,
+        fingerprint: Option<String>,
+        f: impl FnOnce(&mut MysqlConnection) -> QueryResult<R>,
+    ) -> QueryResult<R> {
+        let mut fields = SpanFields::default();
+        connection::record_transaction(&mut self.inner, false, &mut fields);
+
+        let entered = span.enter();
+        hooks::start(&self.config, "mysql", &fields);
+        let start = std::time::Instant::now();
+        let result = traced::named(&mut fields, || f(&mut self.inner));
+        let elapsed = start.elapsed();
+        drop(entered);
+        let span = span.outcome(result.is_err());
+        let _entered = span.enter();
+        summary::record(elapsed, result.is_err());
+        #[cfg(feature = "metrics")]
+        meter::query(
+            "mysql",
+            self.info.current_database.as_deref(),
+            span,
+            elapsed,
+            result.is_err(),
+        );
+        slow::check(&self.config, elapsed, None::<&str>);
+        #[cfg(feature = "registry")]
+        self.registration.record_query();
+
+        if let Some(fingerprint) = fingerprint {
+            fields.push("db.query.fingerprint", fingerprint);
+        }
+        if let Some(rows) = result.as_ref().ok().and_then(RowCount::row_count) {
+            fields.push("db.response.rows", rows);
+            if read {
+                fields.push("db.result", if rows == 0 { "empty" } else { "rows" });
+            }
+        }
+        let error = result.as_ref().err();
+        fields.push_status(error);
+        let fields = self.record_fields(span, fields);
+        fields.log(&self.config, error);
+        hooks::end(&self.config, "mysql", &fields, elapsed, error);
+
+        result
+    }
+
+    /// Completes `fields` with the connection fields, records them on `span`
+    /// and returns them.
+    fn record_fields<'a>(&'a self, span: &Span, mut fields: SpanFields<'a>) -> SpanFields<'a> {
+        self.info.record_fields(&mut fields);
+        if let Some(id) = self.config.correlation_id() {
+            fields.push("correlation_id", id);
+        }
+        if self.test_transaction {
+            fields.push("db.test_transaction", true);
+        }
+        fields.record(span, &self.config);
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+
+    #[test]
+    fn test_get_info_on_establish() {
+        let url = std::env::var("MYSQL_URL").expect("no mysql env var specified");
+        let conn =
+            InstrumentedMysqlConnection::establish(&url).expect("failed to establish connection");
+        assert!(!conn.info.version.is_empty());
+    }
+
+    #[test]
+    fn test_execute_records_connection_fields() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("MYSQL_URL").expect("no mysql env var specified");
+        let mut conn =
+            InstrumentedMysqlConnection::establish(&url).expect("failed to establish connection");
+        let version = conn.info.version.clone();
+        sql_query("SELECT 1")
+            .execute(&mut conn)
+            .expect("failed to execute");
+
+        let span = capture.span("execute_returning_count");
+        assert_eq!(span.field("db.system"), Some("mysql"));
+        assert_eq!(span.field("db.version"), Some(version.as_str()));
+        assert!(span.field("net.peer.port").is_some());
     }
 }
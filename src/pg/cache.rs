@@ -0,0 +1,123 @@
+//! Tracking of the statements in diesel's prepared statement cache, which has
+//! no API to ask it, to record whether the statement of a typed query was
+//! found there.
+
+use diesel::connection::CacheSize;
+use diesel::pg::{Pg, PgQueryBuilder};
+use diesel::query_builder::{QueryBuilder, QueryFragment, QueryId};
+use std::any::TypeId;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+/// What diesel keys a cached statement by: the type of a query with a static
+/// query id, or otherwise its SQL.
+#[derive(Hash)]
+enum Key {
+    Type(TypeId),
+    Sql(String),
+}
+
+/// The keys of the statements a connection has cached, as diesel caches
+/// them.
+///
+/// The cache of a connection wrapped once it is established is taken to be
+/// empty, so its first statements are counted as misses.
+pub(super) struct StatementCache {
+    keys: HashSet<u64>,
+    size: CacheSize,
+}
+
+impl StatementCache {
+    pub(super) fn new(size: CacheSize) -> Self {
+        StatementCache {
+            keys: HashSet::new(),
+            size,
+        }
+    }
+
+    pub(super) fn size(&self) -> CacheSize {
+        self.size
+    }
+
+    /// Changes the size of the cache, which like diesel's is emptied.
+    pub(super) fn resize(&mut self, size: CacheSize) {
+        self.keys.clear();
+        self.size = size;
+    }
+
+    /// The key of the statement of `query` in the cache, or `None` if diesel
+    /// does not cache it: because caching is disabled, the query is unsafe
+    /// to cache, or it is `commented`, which changes its text every time.
+    pub(super) fn key<T: QueryFragment<Pg> + QueryId>(
+        &self,
+        query: &T,
+        commented: bool,
+    ) -> Option<u64> {
+        if commented
+            || matches!(self.size, CacheSize::Disabled)
+            || !query.is_safe_to_cache_prepared(&Pg).ok()?
+        {
+            return None;
+        }
+        let key = match T::query_id() {
+            Some(id) => Key::Type(id),
+            None => {
+                let mut builder = PgQueryBuilder::default();
+                query.to_sql(&mut builder, &Pg).ok()?;
+                Key::Sql(builder.finish())
+            }
+        };
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Looks up the statement with `key`, which is cached from then on, and
+    /// returns whether it was found.
+    pub(super) fn lookup(&mut self, key: u64) -> bool {
+        !self.keys.insert(key)
+    }
+
+    /// Removes the statement with `key`, which the server has discarded.
+    pub(super) fn evict(&mut self, key: u64) {
+        self.keys.remove(&key);
+    }
+}
+
+/// `key` as recorded in `db.statement_cache.key`, 16 hexadecimal digits that
+/// are the same for a statement on every connection of the process.
+pub(super) fn key_name(key: u64) -> String {
+    format!("{:016x}", key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::dsl::sql;
+    use diesel::expression::IntoSql;
+    use diesel::sql_query;
+    use diesel::sql_types::Integer;
+
+    #[test]
+    fn test_statements_are_cached_as_diesel_caches_them() {
+        let mut cache = StatementCache::new(CacheSize::Unbounded);
+        let typed = diesel::select(1.into_sql::<Integer>());
+        let key = cache.key(&typed, false).expect("typed query is cached");
+        assert!(!cache.lookup(key));
+        assert!(cache.lookup(key));
+        assert_eq!(cache.key(&typed, false), Some(key));
+        assert_eq!(cache.key(&typed, true), None);
+
+        let untyped = diesel::select(sql::<Integer>("2"));
+        let untyped_key = cache.key(&untyped, false).expect("sql is cached");
+        assert_ne!(untyped_key, key);
+        assert!(!cache.lookup(untyped_key));
+        assert_eq!(cache.key(&sql_query("SELECT 3"), false), None);
+
+        cache.evict(key);
+        assert!(!cache.lookup(key));
+        cache.resize(CacheSize::Disabled);
+        assert_eq!(cache.key(&typed, false), None);
+    }
+}
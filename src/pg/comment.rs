@@ -0,0 +1,123 @@
+use crate::config::{SqlCommenter, TracingConfig};
+use crate::guard;
+use diesel::pg::Pg;
+use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+use diesel::result::QueryResult;
+use std::fmt::Write;
+
+/// Percent encodes `value` as SQLCommenter requires of the values of its
+/// tags, which also keeps quotes and the end of the comment out of them.
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(char::from(byte));
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+/// Formats `tags` as a SQLCommenter comment, with the tags sorted by key.
+fn format(tags: &mut [(&str, String)]) -> String {
+    tags.sort_by(|a, b| a.0.cmp(b.0));
+    let tags = tags
+        .iter()
+        .map(|(key, value)| format!("{}='{}'", key, encode(value)))
+        .collect::<Vec<_>>();
+    format!("/*{}*/", tags.join(","))
+}
+
+/// The comment to append to the statement about to run, if configured to.
+///
+/// The trace context is read when this is called, so it should be called in
+/// the span of the statement.
+pub(super) fn for_statement(config: &TracingConfig) -> Option<String> {
+    let SqlCommenter {
+        application,
+        traceparent,
+    } = config.sql_commenter.as_ref()?;
+
+    let mut tags = vec![("application", application.clone())];
+    let read = &traceparent.0;
+    if let Some(traceparent) = guard::guarded("traceparent", || read()).flatten() {
+        tags.push(("traceparent", traceparent));
+    }
+    Some(format(&mut tags))
+}
+
+/// Appends `comment` to the raw statement `sql`, before its terminating
+/// semicolon if it has one.
+pub(super) fn append(sql: &str, comment: &str) -> String {
+    let sql = sql.trim_end();
+    match sql.strip_suffix(';') {
+        Some(statement) => format!("{} {};", statement, comment),
+        None => format!("{} {}", sql, comment),
+    }
+}
+
+/// A typed query with a comment appended to it.
+///
+/// The text of the query changes with its comment, so it is never cached as
+/// a prepared statement.
+pub(super) struct Commented<T> {
+    query: T,
+    comment: String,
+}
+
+impl<T> Commented<T> {
+    pub(super) fn new(query: T, comment: String) -> Self {
+        Commented { query, comment }
+    }
+}
+
+impl<T: Query> Query for Commented<T> {
+    type SqlType = T::SqlType;
+}
+
+impl<T> QueryId for Commented<T> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T: QueryFragment<Pg>> QueryFragment<Pg> for Commented<T> {
+    fn walk_ast<'b>(&'b self, mut pass: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        pass.unsafe_to_cache_prepared();
+        self.query.walk_ast(pass.reborrow())?;
+        pass.push_sql(" ");
+        pass.push_sql(&self.comment);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment_is_sorted_and_encoded() {
+        let mut tags = vec![
+            (
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_owned(),
+            ),
+            ("application", "billing api's */ worker".to_owned()),
+        ];
+        assert_eq!(
+            format(&mut tags),
+            "/*application='billing%20api%27s%20%2A%2F%20worker',\
+             traceparent='00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01'*/"
+        );
+    }
+
+    #[test]
+    fn test_comment_goes_before_semicolon() {
+        assert_eq!(append("SELECT 1;\n", "/*a='b'*/"), "SELECT 1 /*a='b'*/;");
+        assert_eq!(append("SELECT 1", "/*a='b'*/"), "SELECT 1 /*a='b'*/");
+    }
+}
@@ -0,0 +1,64 @@
+use crate::fingerprint;
+use crate::statement;
+use diesel::connection::{AnsiTransactionManager, TransactionManager};
+use diesel::pg::PgConnection;
+use diesel::result::QueryResult;
+use diesel::sql_types::Text;
+use diesel::{sql_query, RunQueryDsl};
+use std::time::Duration;
+
+#[derive(QueryableByName)]
+struct Plan {
+    #[diesel(sql_type = Text)]
+    #[diesel(column_name = "QUERY PLAN")]
+    plan: String,
+}
+
+/// The statement of `sql` to explain if it runs slowly, which only `SELECT`
+/// statements are, without the binds diesel's debug output appends.
+pub(super) fn statement(sql: &str) -> Option<String> {
+    (statement::operation(sql) == Some("SELECT")).then(|| {
+        sql.rsplit_once(" -- binds: ")
+            .map_or(sql, |(sql, _)| sql)
+            .to_owned()
+    })
+}
+
+/// Plans `sql` with `EXPLAIN (FORMAT JSON)`, returning the plan.
+///
+/// The binds of the statement are gone by the time it has run, so
+/// statements with placeholders are planned with `GENERIC_PLAN`, which needs
+/// PostgreSQL 16. The statement is only planned, not run, and the planning
+/// is cut short past `timeout` by a `statement_timeout` set in a transaction,
+/// or a savepoint within the current one, which is rolled back to undo it.
+pub(super) fn plan(conn: &mut PgConnection, sql: &str, timeout: Duration) -> QueryResult<String> {
+    let options = if fingerprint::placeholders(sql) > 0 {
+        "GENERIC_PLAN, FORMAT JSON"
+    } else {
+        "FORMAT JSON"
+    };
+
+    AnsiTransactionManager::begin_transaction(conn)?;
+    let plan = sql_query(format!(
+        "SET LOCAL statement_timeout = {}",
+        timeout.as_millis().max(1)
+    ))
+    .execute(conn)
+    .and_then(|_| sql_query(format!("EXPLAIN ({}) {}", options, sql)).get_result::<Plan>(conn));
+    AnsiTransactionManager::rollback_transaction(conn)?;
+    Ok(plan?.plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_selects_are_explained() {
+        assert_eq!(
+            statement("SELECT * FROM users WHERE id = $1 -- binds: [1]").as_deref(),
+            Some("SELECT * FROM users WHERE id = $1")
+        );
+        assert_eq!(statement("DELETE FROM users -- binds: []"), None);
+    }
+}
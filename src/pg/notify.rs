@@ -0,0 +1,117 @@
+use super::InstrumentedPgConnection;
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgNotification;
+use diesel::result::QueryResult;
+use tracing::{info, Span};
+
+/// The channels a connection is listening on, and the span covering the
+/// time it listens.
+pub(super) struct Listening {
+    span: Span,
+    channels: Vec<String>,
+}
+
+impl Listening {
+    fn record_channels(&self) {
+        self.span.record(
+            "db.notification.channels",
+            &self.channels.join(",").as_str(),
+        );
+    }
+}
+
+/// Quotes `channel` as an identifier, so that any name can be listened on.
+fn quote(channel: &str) -> String {
+    format!("\"{}\"", channel.replace('"', "\"\""))
+}
+
+impl InstrumentedPgConnection {
+    /// Starts listening for notifications on `channel`.
+    ///
+    /// The first channel listened on opens a `listening` span, recording
+    /// the channels listened on as `db.notification.channels`, which lasts
+    /// until the last of them is unlistened or the connection is dropped.
+    /// The notifications received are logged within it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `LISTEN` statement fails.
+    pub fn listen(&mut self, channel: &str) -> QueryResult<()> {
+        self.batch_execute(&format!("LISTEN {}", quote(channel)))?;
+
+        let (site, system) = (self.config.query_site(), self.system());
+        let listening = self.listening.get_or_insert_with(|| Listening {
+            span: wide_span!(
+                site,
+                "listening",
+                { db.system = system },
+                [db.notification.channels]
+            ),
+            channels: Vec::new(),
+        });
+        if !listening
+            .channels
+            .iter()
+            .any(|listened| listened == channel)
+        {
+            listening.channels.push(channel.to_owned());
+        }
+        listening.record_channels();
+        Ok(())
+    }
+
+    /// Stops listening for notifications on `channel`, closing the
+    /// `listening` span if it was the last channel listened on.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the `UNLISTEN` statement fails.
+    pub fn unlisten(&mut self, channel: &str) -> QueryResult<()> {
+        self.batch_execute(&format!("UNLISTEN {}", quote(channel)))?;
+
+        if let Some(listening) = &mut self.listening {
+            listening.channels.retain(|listened| listened != channel);
+            listening.record_channels();
+            if listening.channels.is_empty() {
+                self.listening = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// The notifications received since they were last polled, without
+    /// blocking, each logged in the `listening` span with its channel as
+    /// `db.notification.channel`, the length of its payload as
+    /// `db.notification.payload_length` and the process id of the backend
+    /// that sent it as `db.notification.pid`.
+    pub fn notifications(&mut self) -> impl Iterator<Item = QueryResult<PgNotification>> + '_ {
+        let span = self
+            .listening
+            .as_ref()
+            .map_or_else(Span::none, |listening| listening.span.clone());
+        self.inner
+            .notifications_iter()
+            .inspect(move |notification| {
+                if let Ok(notification) = notification {
+                    info!(
+                        parent: &span,
+                        db.notification.channel = %notification.channel,
+                        db.notification.payload_length = notification.payload.len(),
+                        db.notification.pid = notification.process_id,
+                        "notification received",
+                    );
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channels_are_quoted() {
+        assert_eq!(quote("jobs"), "\"jobs\"");
+        assert_eq!(quote("odd\"name"), "\"odd\"\"name\"");
+    }
+}
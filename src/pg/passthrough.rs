@@ -0,0 +1,103 @@
+//! The connection traits of `InstrumentedPgConnection` with the `noop`
+//! feature, which pass every operation straight through to the inner
+//! connection without recording anything.
+
+use super::{InstrumentedPgConnection, InstrumentedTransactionManager};
+use crate::config::TracingConfig;
+use diesel::connection::{
+    CacheSize, Connection, DefaultLoadingMode, Instrumentation, LoadConnection, SimpleConnection,
+    TransactionManager,
+};
+use diesel::expression::QueryMetadata;
+use diesel::pg::{Pg, PgConnection, PgRowByRowLoadingMode};
+use diesel::query_builder::{Query, QueryFragment, QueryId};
+use diesel::r2d2::R2D2Connection;
+use diesel::result::{ConnectionResult, QueryResult};
+
+impl SimpleConnection for InstrumentedPgConnection {
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        self.inner.batch_execute(query)
+    }
+}
+
+impl Connection for InstrumentedPgConnection {
+    type Backend = Pg;
+    type TransactionManager = InstrumentedTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<InstrumentedPgConnection> {
+        InstrumentedPgConnection::establish_with_config(database_url, TracingConfig::global())
+    }
+
+    #[doc(hidden)]
+    fn execute_returning_count<T>(&mut self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Pg> + QueryId,
+    {
+        self.inner.execute_returning_count(source)
+    }
+
+    #[doc(hidden)]
+    fn transaction_state(
+        &mut self,
+    ) -> &mut <Self::TransactionManager as TransactionManager<Self>>::TransactionStateData {
+        self.inner.transaction_state()
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        self.inner.instrumentation()
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.inner.set_instrumentation(instrumentation);
+    }
+
+    fn set_prepared_statement_cache_size(&mut self, size: CacheSize) {
+        self.inner.set_prepared_statement_cache_size(size);
+    }
+}
+
+impl LoadConnection for InstrumentedPgConnection {
+    type Cursor<'conn, 'query> =
+        <PgConnection as LoadConnection<DefaultLoadingMode>>::Cursor<'conn, 'query>;
+    type Row<'conn, 'query> =
+        <PgConnection as LoadConnection<DefaultLoadingMode>>::Row<'conn, 'query>;
+
+    fn load<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> QueryResult<Self::Cursor<'conn, 'query>>
+    where
+        T: Query + QueryFragment<Pg> + QueryId + 'query,
+        Pg: QueryMetadata<T::SqlType>,
+    {
+        LoadConnection::<DefaultLoadingMode>::load(&mut self.inner, source)
+    }
+}
+
+impl LoadConnection<PgRowByRowLoadingMode> for InstrumentedPgConnection {
+    type Cursor<'conn, 'query> =
+        <PgConnection as LoadConnection<PgRowByRowLoadingMode>>::Cursor<'conn, 'query>;
+    type Row<'conn, 'query> =
+        <PgConnection as LoadConnection<PgRowByRowLoadingMode>>::Row<'conn, 'query>;
+
+    fn load<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> QueryResult<Self::Cursor<'conn, 'query>>
+    where
+        T: Query + QueryFragment<Pg> + QueryId + 'query,
+        Pg: QueryMetadata<T::SqlType>,
+    {
+        LoadConnection::<PgRowByRowLoadingMode>::load(&mut self.inner, source)
+    }
+}
+
+impl R2D2Connection for InstrumentedPgConnection {
+    fn ping(&mut self) -> QueryResult<()> {
+        self.inner.ping()
+    }
+
+    fn is_broken(&mut self) -> bool {
+        self.inner.is_broken()
+    }
+}
@@ -0,0 +1,312 @@
+use super::{establish_failure_cause, millis, transaction, InstrumentedPgConnection};
+use crate::config::TracingConfig;
+use diesel::connection::Connection;
+use diesel::result::{ConnectionError, ConnectionResult, DatabaseErrorKind, Error, QueryResult};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How `InstrumentedPgConnection::retry_transaction` retries transactions
+/// that fail on a serialization failure or a deadlock, and
+/// `InstrumentedPgConnection::establish_with_retry` connections that fail to
+/// be established for a reason that is likely to pass.
+///
+/// The wait before each retry doubles from the initial backoff, up to the
+/// maximum backoff.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Gives up after `attempts` attempts, counting the first, 3 by default.
+    #[must_use]
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Waits `backoff` before the first retry, 10ms by default, and at most
+    /// `max_backoff` before any retry, 1s by default.
+    #[must_use]
+    pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// The wait before the `retry`th retry.
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2_u32.saturating_pow(retry - 1))
+            .min(self.max_backoff)
+    }
+}
+
+/// Why a transaction that failed with `error` is worth retrying, if it is.
+///
+/// diesel has no error kind for deadlocks, which are told apart by the
+/// message the server reports them with.
+fn retry_reason(error: &Error) -> Option<&'static str> {
+    match error {
+        Error::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => {
+            Some("serialization_failure")
+        }
+        Error::DatabaseError(_, info) if info.message().starts_with("deadlock detected") => {
+            Some("deadlock")
+        }
+        _ => None,
+    }
+}
+
+/// Why establishing a connection that failed with `error` is worth
+/// retrying, if it is: the server is out of connections, still starting up
+/// or cannot be reached yet.
+///
+/// Other failures, such as a rejected password or a database that does not
+/// exist, would fail the same way again.
+fn establish_retry_reason(error: &ConnectionError) -> Option<&'static str> {
+    if let Some(cause) = establish_failure_cause(error) {
+        return Some(cause);
+    }
+    match error {
+        ConnectionError::BadConnection(message) => {
+            if message.contains("the database system is starting up") {
+                Some("starting_up")
+            } else if message.contains("Connection refused")
+                || message.contains("could not connect to server")
+                || message.contains("timeout expired")
+            {
+                Some("unreachable")
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+impl InstrumentedPgConnection {
+    /// Establishes a connection with the global configuration, trying again
+    /// as `policy` allows while it fails for a reason that is likely to
+    /// pass, such as the server being out of connections or still starting
+    /// up.
+    ///
+    /// Each attempt gets an `establish_attempt` span recording its number as
+    /// `db.connection.attempt` and, if it is retried, why as
+    /// `db.retry.reason`. They nest under an `establish_with_retry` span that
+    /// records the number of attempts made as `db.connection.attempts` and
+    /// how long it took to connect, retries included, as
+    /// `db.connection.time_to_connect_ms`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt, once it fails with an error
+    /// that is not retried or no attempts are left.
+    pub fn establish_with_retry(
+        database_url: &str,
+        policy: RetryPolicy,
+    ) -> ConnectionResult<InstrumentedPgConnection> {
+        let site = TracingConfig::global().establish_site();
+        let span = wide_span!(
+            site,
+            "establish_with_retry",
+            { db.system = "postgresql" },
+            [
+                db.connection.attempts,
+                db.connection.time_to_connect_ms,
+                otel.status_code,
+            ]
+        );
+        let _entered = span.enter();
+
+        let start = Instant::now();
+        let mut attempt = 1;
+        let result = loop {
+            let attempt_span = wide_span!(
+                site,
+                "establish_attempt",
+                {
+                    db.system = "postgresql",
+                    db.connection.attempt = attempt,
+                },
+                [db.retry.reason]
+            );
+            let result =
+                attempt_span.in_scope(|| InstrumentedPgConnection::establish(database_url));
+            match result.as_ref().err().and_then(establish_retry_reason) {
+                Some(reason) if attempt < policy.max_attempts => {
+                    let delay = policy.delay(attempt);
+                    attempt_span.record("db.retry.reason", reason);
+                    warn!(
+                        parent: &attempt_span,
+                        db.retry.reason = reason,
+                        db.retry.backoff_ms = millis(delay),
+                        "retrying connection",
+                    );
+                    drop(attempt_span);
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                _ => break result,
+            }
+        };
+
+        span.record("db.connection.attempts", attempt);
+        match &result {
+            Ok(_) => span.record("db.connection.time_to_connect_ms", millis(start.elapsed())),
+            Err(_) => span.record("otel.status_code", "ERROR"),
+        };
+        result
+    }
+
+    /// Runs `f` in a transaction, running it again in a new transaction if
+    /// it fails on a serialization failure or a deadlock, as `policy` allows.
+    ///
+    /// Each attempt gets a `transaction_attempt` span recording its number as
+    /// `db.transaction.attempt` and, if it is retried, why as
+    /// `db.retry.reason`. They nest under a `retry_transaction` span that
+    /// records the number of attempts made as `db.transaction.attempts`.
+    ///
+    /// Within another transaction the failure dooms the outer transaction as
+    /// well, so `f` is only run once.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the last attempt, once it fails with an error
+    /// that is not retried or no attempts are left.
+    pub fn retry_transaction<T, F>(&mut self, policy: RetryPolicy, mut f: F) -> QueryResult<T>
+    where
+        F: FnMut(&mut Self) -> QueryResult<T>,
+    {
+        let (site, system) = (self.config.query_site(), self.system());
+        let span = wide_span!(
+            site,
+            "retry_transaction",
+            { db.system = system },
+            [db.transaction.attempts, otel.status_code]
+        );
+        let _entered = span.enter();
+
+        let max_attempts = if transaction::depth(self) == 0 {
+            policy.max_attempts
+        } else {
+            1
+        };
+        let mut attempt = 1;
+        let result = loop {
+            let attempt_span = wide_span!(
+                site,
+                "transaction_attempt",
+                {
+                    db.system = system,
+                    db.transaction.attempt = attempt,
+                },
+                [db.retry.reason]
+            );
+            let result = attempt_span.in_scope(|| self.transaction(&mut f));
+            match result.as_ref().err().and_then(retry_reason) {
+                Some(reason) if attempt < max_attempts => {
+                    let delay = policy.delay(attempt);
+                    attempt_span.record("db.retry.reason", reason);
+                    warn!(
+                        parent: &attempt_span,
+                        db.retry.reason = reason,
+                        db.retry.backoff_ms = u64::try_from(delay.as_millis()).unwrap_or(u64::MAX),
+                        "retrying transaction",
+                    );
+                    drop(attempt_span);
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                _ => break result,
+            }
+        };
+
+        span.record("db.transaction.attempts", attempt);
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(50));
+        let delays: Vec<_> = (1..=4).map(|retry| policy.delay(retry)).collect();
+        assert_eq!(delays, [10, 20, 40, 50].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn test_only_contention_is_retried() {
+        let failure =
+            |kind, message: &str| Error::DatabaseError(kind, Box::new(message.to_owned()));
+        assert_eq!(
+            retry_reason(&failure(
+                DatabaseErrorKind::SerializationFailure,
+                "could not serialize access due to concurrent update",
+            )),
+            Some("serialization_failure")
+        );
+        assert_eq!(
+            retry_reason(&failure(DatabaseErrorKind::Unknown, "deadlock detected")),
+            Some("deadlock")
+        );
+        assert_eq!(
+            retry_reason(&failure(
+                DatabaseErrorKind::UniqueViolation,
+                "duplicate key value violates unique constraint",
+            )),
+            None
+        );
+        assert_eq!(retry_reason(&Error::NotFound), None);
+    }
+
+    #[test]
+    fn test_only_transient_establish_failures_are_retried() {
+        let failure = |message: &str| ConnectionError::BadConnection(message.to_owned());
+        assert_eq!(
+            establish_retry_reason(&failure("FATAL:  sorry, too many clients already")),
+            Some("server_connection_limit")
+        );
+        assert_eq!(
+            establish_retry_reason(&failure("FATAL:  the database system is starting up")),
+            Some("starting_up")
+        );
+        assert_eq!(
+            establish_retry_reason(&failure(
+                "connection to server at \"localhost\" (127.0.0.1), port 5432 failed: \
+                 Connection refused"
+            )),
+            Some("unreachable")
+        );
+        assert_eq!(
+            establish_retry_reason(&failure(
+                "FATAL:  password authentication failed for user \"app\""
+            )),
+            None
+        );
+        assert_eq!(
+            establish_retry_reason(&ConnectionError::InvalidConnectionUrl("invalid".to_owned())),
+            None
+        );
+    }
+}
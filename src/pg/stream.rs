@@ -0,0 +1,141 @@
+use super::{millis, sqlstate};
+use crate::config::TracingConfig;
+use crate::fields::SpanFields;
+use crate::hooks;
+#[cfg(feature = "metrics")]
+use crate::meter;
+use crate::sampling::QuerySpan;
+use crate::slow;
+use crate::summary;
+use diesel::result::{Error, QueryResult};
+use std::time::{Duration, Instant};
+
+/// The rows of a query loaded with `PgRowByRowLoadingMode`, fetched from the
+/// server as they are iterated over.
+///
+/// The `load` span of the query lasts as long as the rows do. Once they are
+/// dropped it records the number of rows yielded as `db.response.rows` and
+/// how long the first of them took to arrive as `db.response.first_row_ms`,
+/// and the query counts towards the slow query threshold, and the duration
+/// given to the query end hook, from when it was sent until then.
+pub struct StreamedRows<'conn, C> {
+    cursor: C,
+    span: QuerySpan<'conn>,
+    fields: SpanFields<'static>,
+    config: &'conn TracingConfig,
+    system: &'static str,
+    start: Instant,
+    first_row: Option<Duration>,
+    rows: usize,
+    failed: bool,
+}
+
+impl<'conn, C> StreamedRows<'conn, C> {
+    pub(super) fn new(
+        cursor: C,
+        span: QuerySpan<'conn>,
+        fields: SpanFields<'static>,
+        config: &'conn TracingConfig,
+        system: &'static str,
+        start: Instant,
+    ) -> Self {
+        StreamedRows {
+            cursor,
+            span,
+            fields,
+            config,
+            system,
+            start,
+            first_row: None,
+            rows: 0,
+            failed: false,
+        }
+    }
+}
+
+/// Records and logs `error` on `span`, as the outcome of the operation that
+/// collected `fields`.
+pub(super) fn fail(
+    span: &mut QuerySpan<'_>,
+    fields: &mut SpanFields<'static>,
+    config: &TracingConfig,
+    error: &Error,
+) {
+    if let Some(code) = sqlstate(error) {
+        fields.push("db.error.code", code);
+    }
+    fields.push_status(Some(error));
+    let span = span.outcome(true);
+    let _entered = span.enter();
+    fields.record(span, config);
+    fields.log(config, Some(error));
+}
+
+impl<C, R> Iterator for StreamedRows<'_, C>
+where
+    C: Iterator<Item = QueryResult<R>>,
+{
+    type Item = QueryResult<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.cursor.next();
+        match &row {
+            Some(Ok(_)) => {
+                self.rows += 1;
+                if self.first_row.is_none() {
+                    self.first_row = Some(self.start.elapsed());
+                }
+            }
+            // only the first error is logged, as the stream is unusable
+            // after it
+            Some(Err(e)) if !self.failed => {
+                self.failed = true;
+                fail(&mut self.span, &mut self.fields, self.config, e);
+                hooks::end(
+                    self.config,
+                    self.system,
+                    &self.fields,
+                    self.start.elapsed(),
+                    Some(e),
+                );
+            }
+            _ => {}
+        }
+        row
+    }
+}
+
+impl<C> Drop for StreamedRows<'_, C> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        summary::record(elapsed, self.failed);
+        #[cfg(feature = "metrics")]
+        meter::query(
+            self.system,
+            self.fields
+                .get("db.name")
+                .map(ToString::to_string)
+                .as_deref(),
+            &self.span,
+            elapsed,
+            self.failed,
+        );
+        let _entered = self.span.enter();
+        slow::check(self.config, elapsed, self.fields.get("db.statement"));
+        if self.failed {
+            return;
+        }
+
+        self.fields.push("db.response.rows", self.rows);
+        self.fields
+            .push("db.result", if self.rows == 0 { "empty" } else { "rows" });
+        if let Some(first_row) = self.first_row {
+            self.fields
+                .push("db.response.first_row_ms", millis(first_row));
+        }
+        self.fields.push_status::<Error>(None);
+        self.fields.record(&self.span, self.config);
+        self.fields.log(self.config, None);
+        hooks::end(self.config, self.system, &self.fields, elapsed, None);
+    }
+}
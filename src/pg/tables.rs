@@ -0,0 +1,77 @@
+use diesel::pg::PgConnection;
+use diesel::sql_types::{BigInt, Double, Nullable, Text};
+use diesel::{sql_query, RunQueryDsl};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long statistics are kept before being looked up again.
+const TTL: Duration = Duration::from_secs(60);
+
+/// Maintenance statistics of a table, from `pg_stat_user_tables`.
+#[derive(Clone, Copy, Debug, QueryableByName)]
+pub(super) struct TableStats {
+    #[diesel(sql_type = BigInt)]
+    pub(super) dead_tuples: i64,
+    #[diesel(sql_type = BigInt)]
+    live_tuples: i64,
+    // seconds since the table was last analyzed, manually or automatically
+    #[diesel(sql_type = Nullable<Double>)]
+    last_analyze_age: Option<f64>,
+}
+
+impl TableStats {
+    /// Whether there are more dead tuples than would trigger autovacuum with
+    /// its default settings, suggesting it is not keeping up.
+    pub(super) fn is_problematic(&self) -> bool {
+        // a threshold of 50 tuples plus a scale factor of 0.2
+        self.dead_tuples * 5 > 250 + self.live_tuples
+    }
+
+    /// Whether most of the table is dead tuples.
+    pub(super) fn is_severe(&self) -> bool {
+        self.dead_tuples > 1000 && self.dead_tuples > self.live_tuples
+    }
+
+    pub(super) fn last_analyze_secs(&self) -> Option<u64> {
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        self.last_analyze_age.map(|age| age.max(0.0) as u64)
+    }
+}
+
+/// Statistics of the tables queried on a connection, looked up at most once
+/// per table every `TTL`.
+#[derive(Default)]
+pub(super) struct TableStatsCache {
+    tables: HashMap<String, (Instant, Option<TableStats>)>,
+}
+
+impl TableStatsCache {
+    /// Returns the statistics of `table`, and whether they were just looked
+    /// up, using `conn` to look them up if they are missing or stale.
+    pub(super) fn get(
+        &mut self,
+        conn: &mut PgConnection,
+        table: &str,
+    ) -> (Option<TableStats>, bool) {
+        if let Some((looked_up, stats)) = self.tables.get(table) {
+            if looked_up.elapsed() < TTL {
+                return (*stats, false);
+            }
+        }
+
+        // failures, such as within a failed transaction, are cached too so
+        // they are not retried for every query
+        let stats = sql_query(
+            "SELECT n_dead_tup AS dead_tuples, n_live_tup AS live_tuples, \
+             EXTRACT(EPOCH FROM now() - greatest(last_analyze, last_autoanalyze))::float8 \
+             AS last_analyze_age \
+             FROM pg_stat_user_tables WHERE relname = $1",
+        )
+        .bind::<Text, _>(table)
+        .get_result::<TableStats>(conn)
+        .ok();
+        self.tables
+            .insert(table.to_owned(), (Instant::now(), stats));
+        (stats, true)
+    }
+}
@@ -0,0 +1,110 @@
+use super::{millis, InstrumentedPgConnection};
+use diesel::dsl::sql;
+use diesel::result::{Error, QueryResult};
+use diesel::sql_types::Text;
+use diesel::{sql_query, RunQueryDsl};
+use std::time::Duration;
+use tracing::{debug, error};
+
+/// The message the server cancels a statement running past its
+/// `statement_timeout` with, under the `query_canceled` code 57014 it shares
+/// with statements cancelled by `pg_cancel_backend`.
+const TIMEOUT_MESSAGE: &str = "canceling statement due to statement timeout";
+
+/// Whether `error` is the cancellation of a statement that exceeded its
+/// `statement_timeout`.
+fn is_timeout(error: &Error) -> bool {
+    matches!(error, Error::DatabaseError(_, info) if info.message() == TIMEOUT_MESSAGE)
+}
+
+impl InstrumentedPgConnection {
+    /// Runs `f` with the `statement_timeout` of the session set to
+    /// `timeout`, setting it back to what it was once `f` returns.
+    ///
+    /// The operations of `f` nest under a `statement_timeout` span recording
+    /// the timeout as `db.statement_timeout_ms`. A statement cancelled by the
+    /// timeout logs an error event of its own, with `error.type` set to
+    /// `statement_timeout`, and records `db.statement_timeout.exceeded` on
+    /// the span, telling it apart from other errors and from statements
+    /// cancelled by hand, which the server reports with the same code.
+    ///
+    /// The timeout is set for the session rather than the transaction, so
+    /// it ends with `f` even within a transaction. If a statement of `f`
+    /// fails the current transaction, the timeout is not set back, as the
+    /// server only allows the transaction to roll back, which undoes it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `f`, or of setting the timeout or setting it
+    /// back.
+    pub fn with_statement_timeout<T, F>(&mut self, timeout: Duration, f: F) -> QueryResult<T>
+    where
+        F: FnOnce(&mut Self) -> QueryResult<T>,
+    {
+        // a timeout of zero disables it
+        let timeout_ms = millis(timeout).max(1);
+        let (site, system) = (self.config.query_site(), self.system());
+        let span = wide_span!(
+            site,
+            "statement_timeout",
+            {
+                db.system = system,
+                db.statement_timeout_ms = timeout_ms,
+            },
+            [db.statement_timeout.exceeded, otel.status_code]
+        );
+        let _entered = span.enter();
+
+        let previous = diesel::select(sql::<Text>("current_setting('statement_timeout')"))
+            .get_result::<String>(&mut self.inner)?;
+        debug!(db.statement_timeout_ms = timeout_ms, %previous, "setting statement_timeout");
+        self.set_statement_timeout(&timeout_ms.to_string())?;
+
+        let result = f(self);
+        if let Err(e) = &result {
+            span.record("otel.status_code", "ERROR");
+            if is_timeout(e) {
+                span.record("db.statement_timeout.exceeded", true);
+                error!(
+                    error.type = "statement_timeout",
+                    db.statement_timeout_ms = timeout_ms,
+                    error = %e,
+                    "statement timed out",
+                );
+            }
+        }
+
+        if self.rollback_only {
+            return result;
+        }
+        let reset = self.set_statement_timeout(&previous);
+        let value = result?;
+        reset?;
+        Ok(value)
+    }
+
+    fn set_statement_timeout(&mut self, value: &str) -> QueryResult<()> {
+        sql_query("SELECT set_config('statement_timeout', $1, false)")
+            .bind::<Text, _>(value)
+            .execute(&mut self.inner)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::result::DatabaseErrorKind;
+
+    #[test]
+    fn test_only_timeouts_are_told_apart() {
+        let failure = |message: &str| {
+            Error::DatabaseError(DatabaseErrorKind::Unknown, Box::new(message.to_owned()))
+        };
+        assert!(is_timeout(&failure(TIMEOUT_MESSAGE)));
+        assert!(!is_timeout(&failure(
+            "canceling statement due to user request"
+        )));
+        assert!(!is_timeout(&Error::NotFound));
+    }
+}
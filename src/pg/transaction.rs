@@ -0,0 +1,378 @@
+use super::InstrumentedPgConnection;
+use crate::fields::SpanFields;
+use diesel::connection::{AnsiTransactionManager, TransactionManager, TransactionManagerStatus};
+use diesel::result::{Error, QueryResult};
+use tracing::{debug, error, info};
+
+/// The transaction manager of `InstrumentedPgConnection`, logging an event
+/// for each transaction or savepoint that is started, committed, released or
+/// rolled back.
+///
+/// The events are logged within the span of the transaction, so that they
+/// sit alongside the spans of the statements run in it. Commits and
+/// rollbacks are logged at INFO level, with their outcome and why the
+/// transaction rolled back, so that they can be counted from the logs even
+/// where the spans of transactions are not recorded. Managing the
+/// transaction itself is left to diesel's `AnsiTransactionManager`.
+#[derive(Debug, Default)]
+pub struct InstrumentedTransactionManager;
+
+/// The depth of the current transaction, 0 outside of one.
+pub(super) fn depth(conn: &mut InstrumentedPgConnection) -> u32 {
+    AnsiTransactionManager::transaction_manager_status_mut(&mut conn.inner)
+        .transaction_depth()
+        .ok()
+        .flatten()
+        .map_or(0, std::num::NonZeroU32::get)
+}
+
+/// The name diesel gives the savepoint of the nested transaction at `depth`,
+/// or `None` for the outermost transaction.
+pub(super) fn savepoint_name(depth: u32) -> Option<String> {
+    (depth > 1).then(|| format!("diesel_savepoint_{}", depth - 1))
+}
+
+/// Why a transaction is rolled back, logged with its rollback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum RollbackReason {
+    /// The closure run in the transaction returned an error.
+    Error,
+    /// A statement of the transaction failed, with the given SQLSTATE where
+    /// it is known, after which the server only allows it to roll back.
+    FailedStatement(Option<&'static str>),
+}
+
+impl RollbackReason {
+    /// The reason as recorded in `db.transaction.rollback_reason`.
+    fn name(self) -> &'static str {
+        match self {
+            RollbackReason::Error => "error",
+            RollbackReason::FailedStatement(_) => "failed_statement",
+        }
+    }
+
+    /// The SQLSTATE of the statement that failed, recorded as `db.error.code`.
+    fn code(self) -> Option<&'static str> {
+        match self {
+            RollbackReason::Error => None,
+            RollbackReason::FailedStatement(code) => code,
+        }
+    }
+}
+
+/// Logs that `action` was taken on the transaction at `depth`, or on its
+/// savepoint if it is nested, where committing releases the savepoint.
+fn log(action: &'static str, depth: u32, result: &QueryResult<()>) {
+    if cfg!(feature = "noop") {
+        return;
+    }
+    let savepoint = savepoint_name(depth);
+    let (subject, action) = subject(savepoint.as_deref(), action);
+    match result {
+        Ok(()) => debug!(
+            db.transaction.depth = depth,
+            db.savepoint.name = savepoint.as_deref(),
+            "{} {}",
+            subject,
+            action,
+        ),
+        Err(e) => error!(
+            db.transaction.depth = depth,
+            db.savepoint.name = savepoint.as_deref(),
+            error = %e,
+            "{} {} failed",
+            subject,
+            action,
+        ),
+    }
+}
+
+/// What `action` is taken on, a transaction or the savepoint `savepoint` of
+/// a nested one, and what it is called for it.
+fn subject(savepoint: Option<&str>, action: &'static str) -> (&'static str, &'static str) {
+    match (savepoint, action) {
+        (Some(_), "commit") => ("savepoint", "release"),
+        (Some(_), action) => ("savepoint", action),
+        (None, action) => ("transaction", action),
+    }
+}
+
+/// The outcome of ending the transaction at `depth` by committing it, if
+/// `commit`, or by rolling it back, when a failed statement left it only
+/// able to roll back if `rollback_only`.
+pub(super) fn outcome(commit: bool, depth: u32, rollback_only: bool) -> &'static str {
+    match (commit, rollback_only) {
+        (true, false) => "commit",
+        // the server turns the commit of a failed transaction into a
+        // rollback, while releasing the savepoint of a failed nested one
+        // fails instead
+        (true, true) if depth == 1 => "forced_rollback",
+        _ => "rollback",
+    }
+}
+
+/// Logs the commit or rollback `action` on the transaction of `conn` at
+/// `depth`, with its outcome and the reason it rolled back, if any.
+fn log_outcome(
+    conn: &mut InstrumentedPgConnection,
+    action: &'static str,
+    depth: u32,
+    result: &QueryResult<()>,
+) {
+    let reason = conn.rollback_reason.take();
+    if cfg!(feature = "noop") || result.is_err() {
+        log(action, depth, result);
+        return;
+    }
+    let outcome = outcome(action == "commit", depth, conn.rollback_only);
+    let reason = reason.filter(|_| outcome != "commit");
+    let savepoint = savepoint_name(depth);
+    let (subject, action) = subject(savepoint.as_deref(), action);
+    info!(
+        db.system = conn.system(),
+        db.name = conn.database(),
+        db.transaction.depth = depth,
+        db.savepoint.name = savepoint.as_deref(),
+        db.transaction.outcome = outcome,
+        db.transaction.rollback_reason = reason.map(RollbackReason::name),
+        db.error.code = reason.and_then(RollbackReason::code),
+        "{} {}",
+        subject,
+        action,
+    );
+}
+
+impl TransactionManager<InstrumentedPgConnection> for InstrumentedTransactionManager {
+    type TransactionStateData = AnsiTransactionManager;
+
+    fn begin_transaction(conn: &mut InstrumentedPgConnection) -> QueryResult<()> {
+        let result = AnsiTransactionManager::begin_transaction(&mut conn.inner);
+        // the depth of the transaction started, or that failed to start
+        let depth = depth(conn) + u32::from(result.is_err());
+        log("begin", depth, &result);
+        result
+    }
+
+    fn rollback_transaction(conn: &mut InstrumentedPgConnection) -> QueryResult<()> {
+        let depth = depth(conn);
+        let result = AnsiTransactionManager::rollback_transaction(&mut conn.inner);
+        log_outcome(conn, "rollback", depth, &result);
+        result
+    }
+
+    fn commit_transaction(conn: &mut InstrumentedPgConnection) -> QueryResult<()> {
+        let depth = depth(conn);
+        let result = AnsiTransactionManager::commit_transaction(&mut conn.inner);
+        log_outcome(conn, "commit", depth, &result);
+        result
+    }
+
+    fn transaction_manager_status_mut(
+        conn: &mut InstrumentedPgConnection,
+    ) -> &mut TransactionManagerStatus {
+        AnsiTransactionManager::transaction_manager_status_mut(&mut conn.inner)
+    }
+}
+
+/// Runs `f` in a transaction started with the statement `begin`, committing
+/// it if `f` succeeds and rolling it back otherwise, as diesel's
+/// `TransactionBuilder` does.
+pub(super) fn run_with<T, E, F>(
+    conn: &mut InstrumentedPgConnection,
+    begin: &str,
+    f: F,
+) -> Result<T, E>
+where
+    F: FnOnce(&mut InstrumentedPgConnection) -> Result<T, E>,
+    E: From<Error>,
+{
+    let result = AnsiTransactionManager::begin_transaction_sql(&mut conn.inner, begin);
+    let depth = depth(conn) + u32::from(result.is_err());
+    log("begin", depth, &result);
+    result?;
+
+    match f(conn) {
+        Ok(value) => {
+            InstrumentedTransactionManager::commit_transaction(conn)?;
+            Ok(value)
+        }
+        Err(e) => match InstrumentedTransactionManager::rollback_transaction(conn) {
+            Ok(()) | Err(Error::BrokenTransactionManager) => Err(e),
+            Err(rollback_error) => Err(rollback_error.into()),
+        },
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    fn sql(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "read_committed",
+            IsolationLevel::RepeatableRead => "repeatable_read",
+            IsolationLevel::Serializable => "serializable",
+        }
+    }
+}
+
+/// The settings of a transaction started with a builder, those left unset
+/// being the session's defaults.
+#[derive(Clone, Debug, Default)]
+pub(super) struct TransactionSettings {
+    isolation_level: Option<IsolationLevel>,
+    read_only: Option<bool>,
+    deferrable: Option<bool>,
+}
+
+impl TransactionSettings {
+    /// The statement starting a transaction with these settings.
+    pub(super) fn sql(&self) -> String {
+        let mut sql = String::from("BEGIN TRANSACTION");
+        if let Some(level) = self.isolation_level {
+            sql.push_str(" ISOLATION LEVEL ");
+            sql.push_str(level.sql());
+        }
+        match self.read_only {
+            Some(true) => sql.push_str(" READ ONLY"),
+            Some(false) => sql.push_str(" READ WRITE"),
+            None => {}
+        }
+        match self.deferrable {
+            Some(true) => sql.push_str(" DEFERRABLE"),
+            Some(false) => sql.push_str(" NOT DEFERRABLE"),
+            None => {}
+        }
+        sql
+    }
+
+    pub(super) fn record(&self, fields: &mut SpanFields<'_>) {
+        if let Some(level) = self.isolation_level {
+            fields.push("db.transaction.isolation_level", level.name());
+        }
+        if let Some(read_only) = self.read_only {
+            fields.push("db.transaction.read_only", read_only);
+        }
+        if let Some(deferrable) = self.deferrable {
+            fields.push("db.transaction.deferrable", deferrable);
+        }
+    }
+}
+
+/// Builds a transaction with settings of its own, returned by
+/// `InstrumentedPgConnection::build_transaction`.
+///
+/// This mirrors diesel's `TransactionBuilder`, except that the closure given
+/// to `run` receives the instrumented connection and the settings are
+/// recorded on the transaction's span.
+#[must_use = "transactions are only started by `run`"]
+pub struct InstrumentedTransactionBuilder<'a> {
+    conn: &'a mut InstrumentedPgConnection,
+    settings: TransactionSettings,
+}
+
+impl<'a> InstrumentedTransactionBuilder<'a> {
+    pub(super) fn new(conn: &'a mut InstrumentedPgConnection) -> Self {
+        InstrumentedTransactionBuilder {
+            conn,
+            settings: TransactionSettings::default(),
+        }
+    }
+
+    /// Makes the transaction `READ ONLY`.
+    pub fn read_only(mut self) -> Self {
+        self.settings.read_only = Some(true);
+        self
+    }
+
+    /// Makes the transaction `READ WRITE`.
+    pub fn read_write(mut self) -> Self {
+        self.settings.read_only = Some(false);
+        self
+    }
+
+    /// Makes the transaction `DEFERRABLE`.
+    pub fn deferrable(mut self) -> Self {
+        self.settings.deferrable = Some(true);
+        self
+    }
+
+    /// Makes the transaction `NOT DEFERRABLE`.
+    pub fn not_deferrable(mut self) -> Self {
+        self.settings.deferrable = Some(false);
+        self
+    }
+
+    /// Runs the transaction at the `READ COMMITTED` isolation level.
+    pub fn read_committed(mut self) -> Self {
+        self.settings.isolation_level = Some(IsolationLevel::ReadCommitted);
+        self
+    }
+
+    /// Runs the transaction at the `REPEATABLE READ` isolation level.
+    pub fn repeatable_read(mut self) -> Self {
+        self.settings.isolation_level = Some(IsolationLevel::RepeatableRead);
+        self
+    }
+
+    /// Runs the transaction at the `SERIALIZABLE` isolation level.
+    pub fn serializable(mut self) -> Self {
+        self.settings.isolation_level = Some(IsolationLevel::Serializable);
+        self
+    }
+
+    /// Runs `f` in a transaction with the settings built, committing it if
+    /// `f` succeeds and rolling it back otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of `f`, or the error starting, committing or rolling
+    /// back the transaction.
+    pub fn run<T, E, F>(self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut InstrumentedPgConnection) -> Result<T, E>,
+        E: From<Error>,
+    {
+        self.conn.run_transaction(Some(&self.settings), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_render_begin_statement() {
+        assert_eq!(TransactionSettings::default().sql(), "BEGIN TRANSACTION");
+        let settings = TransactionSettings {
+            isolation_level: Some(IsolationLevel::Serializable),
+            read_only: Some(true),
+            deferrable: Some(true),
+        };
+        assert_eq!(
+            settings.sql(),
+            "BEGIN TRANSACTION ISOLATION LEVEL SERIALIZABLE READ ONLY DEFERRABLE"
+        );
+    }
+
+    #[test]
+    fn test_only_outermost_commits_are_forced_to_roll_back() {
+        assert_eq!(outcome(true, 1, false), "commit");
+        assert_eq!(outcome(true, 1, true), "forced_rollback");
+        assert_eq!(outcome(true, 2, true), "rollback");
+        assert_eq!(outcome(false, 1, true), "rollback");
+        assert_eq!(outcome(false, 2, false), "rollback");
+    }
+}
@@ -0,0 +1,68 @@
+use diesel::pg::PgConnection;
+use diesel::sql_types::{Integer, Nullable, Text};
+use diesel::{sql_query, RunQueryDsl};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tracing::debug;
+
+/// Roughly how long a backend spent waiting, by the kind of wait.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct WaitTimes {
+    pub(super) io: Duration,
+    pub(super) lock: Duration,
+}
+
+#[derive(QueryableByName)]
+struct Activity {
+    #[diesel(sql_type = Nullable<Text>)]
+    wait_event_type: Option<String>,
+}
+
+/// Runs `f` while sampling what the backend `pid` is waiting on through the
+/// separate connection `sampler`, every `interval` until `f` returns.
+///
+/// Each sample attributes a whole interval to the kind of wait seen, so the
+/// times are only as precise as the interval.
+pub(super) fn sample<R>(
+    sampler: &mut PgConnection,
+    pid: i32,
+    interval: Duration,
+    f: impl FnOnce() -> R,
+) -> (R, WaitTimes) {
+    let done = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let handle = scope.spawn(|| {
+            let mut times = WaitTimes::default();
+            loop {
+                thread::park_timeout(interval);
+                if done.load(Ordering::Acquire) {
+                    return times;
+                }
+
+                let activity =
+                    sql_query("SELECT wait_event_type FROM pg_stat_activity WHERE pid = $1")
+                        .bind::<Integer, _>(pid)
+                        .get_result::<Activity>(sampler);
+                match activity {
+                    Ok(activity) => match activity.wait_event_type.as_deref() {
+                        Some("IO") => times.io += interval,
+                        Some("Lock") | Some("LWLock") => times.lock += interval,
+                        _ => {}
+                    },
+                    Err(e) => {
+                        debug!(error = %e, "failed to sample wait events");
+                        return times;
+                    }
+                }
+            }
+        });
+
+        let result = f();
+        done.store(true, Ordering::Release);
+        handle.thread().unpark();
+
+        (result, handle.join().unwrap_or_default())
+    })
+}
@@ -0,0 +1,376 @@
+/*!
+Events for the lifecycle of pooled connections.
+
+r2d2 closes idle connections after the pool's idle timeout and every
+connection once it reaches the pool's maximum lifetime. A [`PoolEvents`]
+handler emits an `INFO` event each time a pooled connection is closed,
+recording why as `db.pool.release.reason` along with the connection's age.
+
+r2d2 does not say why it closed a connection, so the reason is worked out
+from the connection's age and how long it had been idle, compared with the
+same settings the pool was built with. These have to be given to the handler
+as well as to the pool.
+
+```no_run
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel_tracing::pg::InstrumentedPgConnection;
+use diesel_tracing::pool::PoolEvents;
+use std::time::Duration;
+
+let lifetime = Duration::from_secs(30 * 60);
+let idle_timeout = Duration::from_secs(10 * 60);
+let pool = Pool::builder()
+    .max_lifetime(Some(lifetime))
+    .idle_timeout(Some(idle_timeout))
+    .event_handler(Box::new(
+        PoolEvents::new()
+            .with_max_lifetime(lifetime)
+            .with_idle_timeout(idle_timeout),
+    ))
+    .build(ConnectionManager::<InstrumentedPgConnection>::new("postgres://localhost"))
+    .unwrap();
+```
+
+Pools run the handler from their own threads, so the events are sent to the
+subscriber that was the default when the handler was created rather than to
+whichever one is current on the pool's threads. The handler also emits an
+event as each connection is added to the pool and a warning each time a
+checkout times out.
+
+An [`InstrumentedPool`] goes further and gives each checkout a `checkout`
+span, recording how long it waited for a connection as `db.pool.wait_ms`,
+whether the connection had just been established as
+`db.pool.connection.new`, and how many connections the pool had.
+
+```no_run
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel_tracing::pg::InstrumentedPgConnection;
+use diesel_tracing::pool::{InstrumentedPool, PoolEvents};
+
+let pool = InstrumentedPool::build(
+    Pool::builder().max_size(10),
+    PoolEvents::new().with_name("primary"),
+    ConnectionManager::<InstrumentedPgConnection>::new("postgres://localhost"),
+)
+.unwrap();
+let conn = pool.get().unwrap();
+```
+*/
+use diesel::r2d2::event::{AcquireEvent, CheckinEvent, CheckoutEvent, ReleaseEvent, TimeoutEvent};
+use diesel::r2d2::{Builder, HandleEvent, ManageConnection, Pool, PoolError, PooledConnection};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug_span, dispatcher, error, field, info, warn, Dispatch, Span};
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// An r2d2 event handler tracing the lifecycle of pooled connections,
+/// including why they are closed.
+#[derive(Debug)]
+pub struct PoolEvents {
+    name: Option<Arc<str>>,
+    max_lifetime: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    // when each connection currently in the pool was put there
+    idle_since: Mutex<HashMap<u64, Instant>>,
+    // the connections yet to be checked out since they were established
+    fresh: Mutex<HashSet<u64>>,
+    dispatch: Dispatch,
+}
+
+impl PoolEvents {
+    /// Creates a handler sending its events to the current default
+    /// subscriber.
+    #[must_use]
+    pub fn new() -> Self {
+        PoolEvents {
+            name: None,
+            max_lifetime: None,
+            idle_timeout: None,
+            idle_since: Mutex::new(HashMap::new()),
+            fresh: Mutex::new(HashSet::new()),
+            dispatch: dispatcher::get_default(Dispatch::clone),
+        }
+    }
+
+    /// Names the pool, recorded as `db.pool.name` on its events and, for an
+    /// [`InstrumentedPool`], on its `checkout` spans.
+    #[must_use]
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into().into());
+        self
+    }
+
+    /// Sets the maximum lifetime the pool was built with.
+    #[must_use]
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Sets the idle timeout the pool was built with.
+    #[must_use]
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    fn set_idle(&self, id: u64, idle: bool) {
+        if let Ok(mut idle_since) = self.idle_since.lock() {
+            if idle {
+                idle_since.insert(id, Instant::now());
+            } else {
+                idle_since.remove(&id);
+            }
+        }
+    }
+
+    /// Why a connection of `age`, idle for `idle` if it was in the pool, was
+    /// closed.
+    fn release_reason(&self, age: Duration, idle: Option<Duration>) -> &'static str {
+        if self.max_lifetime.map_or(false, |lifetime| age >= lifetime) {
+            "max_lifetime"
+        } else if self
+            .idle_timeout
+            .zip(idle)
+            .map_or(false, |(timeout, idle)| idle >= timeout)
+        {
+            "idle_timeout"
+        } else {
+            // broken connections and pools being dropped
+            "closed"
+        }
+    }
+}
+
+impl Default for PoolEvents {
+    fn default() -> Self {
+        PoolEvents::new()
+    }
+}
+
+impl HandleEvent for PoolEvents {
+    fn handle_acquire(&self, event: AcquireEvent) {
+        self.set_idle(event.id(), true);
+        if let Ok(mut fresh) = self.fresh.lock() {
+            fresh.insert(event.id());
+        }
+
+        dispatcher::with_default(&self.dispatch, || {
+            info!(
+                db.pool.name = self.name.as_deref(),
+                db.pool.connection.id = event.id(),
+                "pooled connection acquired",
+            );
+        });
+    }
+
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        self.set_idle(event.id(), false);
+        let fresh = self
+            .fresh
+            .lock()
+            .map_or(false, |mut fresh| fresh.remove(&event.id()));
+        // checkouts are handled on the thread checking out, in the span of
+        // the checkout if it is through an `InstrumentedPool`
+        Span::current().record("db.pool.connection.new", fresh);
+    }
+
+    fn handle_timeout(&self, event: TimeoutEvent) {
+        dispatcher::with_default(&self.dispatch, || {
+            warn!(
+                db.pool.name = self.name.as_deref(),
+                db.pool.timeout_ms = millis(event.timeout()),
+                "pooled connection checkout timed out",
+            );
+        });
+    }
+
+    fn handle_checkin(&self, event: CheckinEvent) {
+        self.set_idle(event.id(), true);
+    }
+
+    fn handle_release(&self, event: ReleaseEvent) {
+        let idle = self
+            .idle_since
+            .lock()
+            .ok()
+            .and_then(|mut idle_since| idle_since.remove(&event.id()))
+            .map(|since| since.elapsed());
+        if let Ok(mut fresh) = self.fresh.lock() {
+            fresh.remove(&event.id());
+        }
+        let reason = self.release_reason(event.age(), idle);
+
+        dispatcher::with_default(&self.dispatch, || {
+            info!(
+                db.pool.name = self.name.as_deref(),
+                db.pool.connection.id = event.id(),
+                db.pool.release.reason = reason,
+                db.connection.age_ms = millis(event.age()),
+                "pooled connection released",
+            );
+        });
+    }
+}
+
+/// An r2d2 pool tracing each checkout of a connection in a span of its own.
+pub struct InstrumentedPool<M: ManageConnection> {
+    pool: Pool<M>,
+    name: Option<Arc<str>>,
+}
+
+impl<M: ManageConnection> InstrumentedPool<M> {
+    /// Builds a pool from `builder` and `manager`, with `events` as its event
+    /// handler, replacing any set on `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pool's initial connections cannot be established.
+    pub fn build(builder: Builder<M>, events: PoolEvents, manager: M) -> Result<Self, PoolError> {
+        let name = events.name.clone();
+        let pool = builder.event_handler(Box::new(events)).build(manager)?;
+
+        Ok(InstrumentedPool { pool, name })
+    }
+
+    /// Checks out a connection as `Pool::get` does, in a `checkout` span.
+    ///
+    /// # Errors
+    ///
+    /// Fails if no connection becomes available within the pool's connection
+    /// timeout.
+    pub fn get(&self) -> Result<PooledConnection<M>, PoolError> {
+        let span = debug_span!(
+            "checkout",
+            db.pool.name = self.name.as_deref(),
+            db.pool.wait_ms = field::Empty,
+            db.pool.connection.new = field::Empty,
+            db.pool.connections = field::Empty,
+            db.pool.idle_connections = field::Empty,
+            otel.status_code = field::Empty,
+        );
+        let _entered = span.enter();
+        let start = Instant::now();
+        let result = self.pool.get();
+
+        span.record("db.pool.wait_ms", millis(start.elapsed()));
+        let state = self.pool.state();
+        span.record("db.pool.connections", state.connections);
+        span.record("db.pool.idle_connections", state.idle_connections);
+        if let Err(e) = &result {
+            span.record("otel.status_code", "ERROR");
+            error!(error = %e, "failed to check out pooled connection");
+        }
+        result
+    }
+
+    /// The pool, for everything else it does.
+    #[must_use]
+    pub fn pool(&self) -> &Pool<M> {
+        &self.pool
+    }
+}
+
+impl<M: ManageConnection> Clone for InstrumentedPool<M> {
+    fn clone(&self) -> Self {
+        InstrumentedPool {
+            pool: self.pool.clone(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pg::InstrumentedPgConnection;
+    use crate::test::Capture;
+    use diesel::connection::{Connection, TransactionManager};
+    use diesel::r2d2::{ConnectionManager, Pool};
+
+    #[test]
+    fn test_release_reasons() {
+        let secs = Duration::from_secs;
+        let events = PoolEvents::new()
+            .with_max_lifetime(secs(60))
+            .with_idle_timeout(secs(10));
+        assert_eq!(events.release_reason(secs(60), None), "max_lifetime");
+        assert_eq!(
+            events.release_reason(secs(60), Some(secs(10))),
+            "max_lifetime"
+        );
+        assert_eq!(
+            events.release_reason(secs(30), Some(secs(10))),
+            "idle_timeout"
+        );
+        assert_eq!(events.release_reason(secs(30), Some(secs(5))), "closed");
+        assert_eq!(events.release_reason(secs(30), None), "closed");
+
+        // without the pool's settings every release is put down to closing
+        let events = PoolEvents::new();
+        assert_eq!(
+            events.release_reason(secs(3600), Some(secs(3600))),
+            "closed"
+        );
+    }
+
+    #[test]
+    fn test_broken_connections_are_released() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let pool = Pool::builder()
+            .max_size(1)
+            .event_handler(Box::new(PoolEvents::new().with_name("primary")))
+            .build(ConnectionManager::<InstrumentedPgConnection>::new(url))
+            .expect("failed to build pool");
+        let mut conn = pool.get().expect("failed to check out connection");
+        // a connection checked in within a transaction is broken
+        <InstrumentedPgConnection as Connection>::TransactionManager::begin_transaction(&mut *conn)
+            .expect("failed to begin transaction");
+        drop(conn);
+
+        let event = capture.event("pooled connection released");
+        assert_eq!(event.field("db.pool.name"), Some("primary"));
+        assert_eq!(event.field("db.pool.release.reason"), Some("closed"));
+        assert!(event.field("db.connection.age_ms").is_some());
+    }
+
+    #[test]
+    fn test_checkouts_are_traced() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let url = std::env::var("POSTGRESQL_URL").expect("no postgresql env var specified");
+        let pool = InstrumentedPool::build(
+            Pool::builder()
+                .max_size(1)
+                .connection_timeout(Duration::from_millis(100)),
+            PoolEvents::new().with_name("primary"),
+            ConnectionManager::<InstrumentedPgConnection>::new(url),
+        )
+        .expect("failed to build pool");
+        capture.event("pooled connection acquired");
+
+        let conn = pool.get().expect("failed to check out connection");
+        let checkouts = capture.spans_named("checkout");
+        assert_eq!(checkouts[0].field("db.pool.name"), Some("primary"));
+        assert_eq!(checkouts[0].field("db.pool.connection.new"), Some("true"));
+        assert!(checkouts[0].field("db.pool.wait_ms").is_some());
+
+        assert!(pool.get().is_err());
+        capture.event("pooled connection checkout timed out");
+        drop(conn);
+
+        pool.get().expect("failed to check out connection again");
+        let checkouts = capture.spans_named("checkout");
+        assert_eq!(checkouts[1].field("otel.status_code"), Some("ERROR"));
+        assert_eq!(checkouts[2].field("db.pool.connection.new"), Some("false"));
+    }
+}
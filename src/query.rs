@@ -0,0 +1,99 @@
+use diesel::query_builder::QueryId;
+use std::any::type_name;
+
+/// The name of the outermost type of `T`, without its path or parameters.
+fn outer_type_name<T>() -> &'static str {
+    let name = type_name::<T>();
+    let outer = name.split('<').next().unwrap_or(name);
+    outer.rsplit("::").next().unwrap_or(outer)
+}
+
+/// Whether `T` is one of diesel's boxed queries, as built by `into_boxed`.
+///
+/// Boxed queries have no static query id so their prepared statements are
+/// never cached. Nothing on the query traits tells them apart from other
+/// queries without a static id, such as `sql_query`, so this falls back to
+/// the name of the outermost type.
+pub(crate) fn is_boxed<T: QueryId>() -> bool {
+    if T::HAS_STATIC_QUERY_ID {
+        return false;
+    }
+
+    outer_type_name::<T>().starts_with("Boxed")
+}
+
+/// The operation performed by `T`, for diesel's own statement types.
+pub(crate) fn operation<T>() -> Option<&'static str> {
+    match outer_type_name::<T>() {
+        "InsertStatement" => Some("INSERT"),
+        "UpdateStatement" => Some("UPDATE"),
+        "DeleteStatement" => Some("DELETE"),
+        "SelectStatement" | "BoxedSelectStatement" => Some("SELECT"),
+        _ => None,
+    }
+}
+
+/// Whether `T` inserts a batch of records, as diesel's inserts of a slice,
+/// `Vec` or array of records do.
+pub(crate) fn is_batch_insert<T>() -> bool {
+    outer_type_name::<T>() == "InsertStatement" && type_name::<T>().contains("BatchInsert<")
+}
+
+/// The table `T` operates on, for queries built from the tables of a
+/// `table!` schema, taken from the first table in the name of its type.
+///
+/// The tables of a schema are types named `table` in a module named after
+/// the table, so the first of them in the name of a query's type is the one
+/// it selects from, inserts into, updates or deletes from.
+pub(crate) fn table<T>() -> Option<&'static str> {
+    let name = type_name::<T>();
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let index = name
+        .match_indices("::table")
+        .map(|(index, _)| index)
+        .find(|&index| {
+            !name[index + "::table".len()..]
+                .chars()
+                .next()
+                .map_or(false, is_word)
+        })?;
+    let table = name[..index].rsplit(|c: char| !is_word(c)).next()?;
+
+    if table.is_empty() {
+        None
+    } else {
+        Some(table)
+    }
+}
+
+/// Results of operations that can tell how many rows they returned or
+/// affected.
+///
+/// This is public but unreachable from outside the crate, as it bounds the
+/// futures of the async connections.
+pub trait RowCount {
+    fn row_count(&self) -> Option<usize>;
+
+    /// The size in bytes of the largest row returned, if it was measured.
+    fn largest_row_bytes(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl RowCount for () {
+    fn row_count(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl RowCount for usize {
+    fn row_count(&self) -> Option<usize> {
+        Some(*self)
+    }
+}
+
+impl<T> RowCount for Vec<T> {
+    fn row_count(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
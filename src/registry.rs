@@ -0,0 +1,115 @@
+/*!
+A process wide registry of the instrumented connections that are alive.
+
+With the `registry` feature enabled, every instrumented connection registers
+itself when it is established and is removed from the registry when it is
+dropped. [`snapshot`] lists the connections alive at the time along with
+what is known about them, for building diagnostic views such as a debug
+endpoint listing a service's database connections.
+
+The registry only holds a description of each connection and never the
+connection itself, so it does not keep connections alive.
+
+```no_run
+for connection in diesel_tracing::registry::snapshot() {
+    println!(
+        "{} connection to {} for {:?}, {} queries",
+        connection.backend, connection.database, connection.age, connection.queries,
+    );
+}
+```
+*/
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+struct Entry {
+    backend: &'static str,
+    database: String,
+    peer: Option<String>,
+    established: Instant,
+    queries: Arc<AtomicU64>,
+}
+
+static CONNECTIONS: Mutex<BTreeMap<u64, Entry>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn connections() -> MutexGuard<'static, BTreeMap<u64, Entry>> {
+    // the map is never left half updated, so it is fine to use after a panic
+    CONNECTIONS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A description of a live instrumented connection.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ConnectionSnapshot {
+    /// Identifies the connection for as long as the process runs.
+    pub id: u64,
+    /// The backend, as recorded in `db.system`.
+    pub backend: &'static str,
+    /// The database connected to, as recorded in `db.name`.
+    pub database: String,
+    /// The address of the server, as recorded in `net.peer.ip`, if known.
+    pub peer: Option<String>,
+    /// How long ago the connection was established.
+    pub age: Duration,
+    /// The number of operations run on the connection.
+    pub queries: u64,
+}
+
+/// Lists the instrumented connections alive in the process.
+#[must_use]
+pub fn snapshot() -> Vec<ConnectionSnapshot> {
+    connections()
+        .iter()
+        .map(|(id, entry)| ConnectionSnapshot {
+            id: *id,
+            backend: entry.backend,
+            database: entry.database.clone(),
+            peer: entry.peer.clone(),
+            age: entry.established.elapsed(),
+            queries: entry.queries.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// A connection's place in the registry, removing it when dropped.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    id: u64,
+    queries: Arc<AtomicU64>,
+}
+
+impl Registration {
+    pub(crate) fn new(backend: &'static str, database: String, peer: Option<String>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let queries = Arc::new(AtomicU64::new(0));
+        let entry = Entry {
+            backend,
+            database,
+            peer,
+            established: Instant::now(),
+            queries: Arc::clone(&queries),
+        };
+        connections().insert(id, entry);
+
+        Registration { id, queries }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Counts an operation run on the connection.
+    pub(crate) fn record_query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        connections().remove(&self.id);
+    }
+}
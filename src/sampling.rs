@@ -0,0 +1,117 @@
+//! Sampling of the query spans of a connection, set with
+//! `TracingConfig::with_query_sampling`.
+
+use crate::config::TracingConfig;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::Span;
+
+/// Counts the queries of a connection to record the span of one in every
+/// so many of them.
+#[derive(Debug, Default)]
+pub(crate) struct SpanSampler {
+    queries: AtomicU64,
+}
+
+impl SpanSampler {
+    /// The span `create` makes for the next query, or a disabled span if it
+    /// is not sampled, so that subscribers never see it unless the query
+    /// fails.
+    ///
+    /// The first query of a connection is always sampled.
+    pub(crate) fn span<'a>(
+        &self,
+        config: &TracingConfig,
+        create: impl FnOnce() -> Span + Send + Sync + 'a,
+    ) -> QuerySpan<'a> {
+        let one_in = match config.query_sampling {
+            Some(one_in) if one_in > 1 => u64::from(one_in),
+            _ => return create().into(),
+        };
+        if self.queries.fetch_add(1, Ordering::Relaxed) % one_in == 0 {
+            create().into()
+        } else {
+            QuerySpan {
+                span: Span::none(),
+                create: Some(Box::new(create)),
+            }
+        }
+    }
+}
+
+/// The span of a query, disabled if the query was not sampled until the
+/// query is known to have failed.
+pub(crate) struct QuerySpan<'a> {
+    span: Span,
+    // makes the span of a query that was not sampled
+    create: Option<Box<dyn FnOnce() -> Span + Send + Sync + 'a>>,
+}
+
+impl QuerySpan<'_> {
+    /// The span to record the outcome of the query on, made now if the query
+    /// was not sampled but `failed`, so that every failure gets a span.
+    ///
+    /// The span made for a failure starts once the query has failed, so its
+    /// duration is not the query's.
+    pub(crate) fn outcome(&mut self, failed: bool) -> &Span {
+        if failed {
+            if let Some(create) = self.create.take() {
+                self.span = create();
+            }
+        }
+        &self.span
+    }
+}
+
+impl From<Span> for QuerySpan<'_> {
+    fn from(span: Span) -> Self {
+        QuerySpan { span, create: None }
+    }
+}
+
+impl Deref for QuerySpan<'_> {
+    type Target = Span;
+
+    fn deref(&self) -> &Span {
+        &self.span
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+
+    #[test]
+    fn test_one_in_every_n_spans_is_sampled() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let sampler = SpanSampler::default();
+        let config = TracingConfig::default().with_query_sampling(3);
+        let sampled: Vec<_> = (0..7)
+            .map(|query| {
+                let span = sampler.span(&config, || tracing::info_span!("query", query));
+                !span.is_none()
+            })
+            .collect();
+        assert_eq!(sampled, [true, false, false, true, false, false, true]);
+        assert_eq!(capture.spans_named("query").len(), 3);
+    }
+
+    #[test]
+    fn test_failed_queries_get_a_span() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let sampler = SpanSampler::default();
+        let config = TracingConfig::default().with_query_sampling(3);
+        let mut sampled = sampler.span(&config, || tracing::info_span!("query"));
+        assert!(!sampled.outcome(false).is_none());
+        let mut succeeded = sampler.span(&config, || tracing::info_span!("query"));
+        assert!(succeeded.outcome(false).is_none());
+        let mut failed = sampler.span(&config, || tracing::info_span!("query"));
+        assert!(!failed.outcome(true).is_none());
+        assert_eq!(capture.spans_named("query").len(), 2);
+    }
+}
@@ -0,0 +1,41 @@
+//! Warnings for operations taking longer than the slow query threshold set
+//! with `TracingConfig::with_slow_query_threshold`.
+
+use crate::config::TracingConfig;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::time::Duration;
+use tracing::{field, warn};
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Warns that an operation which took `elapsed` to complete was slow, if it
+/// reached the threshold, with its statement if it was recorded.
+pub(crate) fn check(config: &TracingConfig, elapsed: Duration, statement: Option<impl Display>) {
+    if let Some(threshold) = config.slow_query.filter(|threshold| elapsed >= *threshold) {
+        warn!(
+            db.duration_ms = millis(elapsed),
+            db.slow_query.threshold_ms = millis(threshold),
+            db.statement = statement.map(field::display),
+            "slow query",
+        );
+    }
+}
+
+/// Warns that an operation was slow if it had been running for `elapsed`
+/// when it was dropped before completing, as the futures of async
+/// connections can be while their query is still running. A load whose
+/// stream of rows is dropped has completed, and goes through `check`.
+#[cfg(feature = "async")]
+pub(crate) fn check_dropped(config: &TracingConfig, elapsed: Duration) {
+    if let Some(threshold) = config.slow_query.filter(|threshold| elapsed >= *threshold) {
+        warn!(
+            db.duration_ms = millis(elapsed),
+            db.slow_query.threshold_ms = millis(threshold),
+            db.cancelled = true,
+            "slow query",
+        );
+    }
+}
@@ -1,23 +1,143 @@
-use diesel::connection::{AnsiTransactionManager, Connection, SimpleConnection};
-use diesel::deserialize::{Queryable, QueryableByName};
-use diesel::query_builder::{AsQuery, QueryFragment, QueryId};
-use diesel::result::Error;
-use diesel::result::{ConnectionResult, QueryResult};
+use crate::config::TracingConfig;
+use crate::connection;
+use crate::fields::SpanFields;
+use crate::fingerprint;
+use crate::guard;
+use crate::hooks;
+#[cfg(feature = "metrics")]
+use crate::meter;
+use crate::query::RowCount;
+use crate::sampling::{QuerySpan, SpanSampler};
+use crate::slow;
+use crate::summary;
+use crate::traced;
+use diesel::connection::{
+    AnsiTransactionManager, BoxableConnection, CacheSize, Connection, Instrumentation,
+    LoadConnection, MultiConnectionHelper, SimpleConnection, TransactionManager,
+};
+use diesel::deserialize::{FromSqlRow, StaticallySizedRow};
+use diesel::expression::QueryMetadata;
+use diesel::migration::MigrationConnection;
+use diesel::query_builder::{Query, QueryFragment, QueryId};
+use diesel::r2d2::R2D2Connection;
+use diesel::result::{ConnectionError, ConnectionResult, Error, QueryResult};
 use diesel::serialize::ToSql;
-use diesel::sql_types::HasSqlType;
+use diesel::sql_types::{HasSqlType, Text, TypeMetadata};
 use diesel::sqlite::{Sqlite, SqliteConnection};
-use tracing::instrument;
+use diesel::{sql_query, RunQueryDsl};
+use std::any::Any;
+use tracing::{debug, error, field, info, Span};
+
+/// Information about the database a connection is established to, recorded
+/// on the connection's spans.
+#[derive(Clone, Debug, PartialEq)]
+struct SqliteConnectionInfo {
+    // db.name
+    database: String,
+    // db.version
+    version: String,
+}
+
+#[derive(QueryableByName)]
+struct SqliteVersion {
+    #[diesel(sql_type = Text)]
+    version: String,
+}
+
+impl SqliteConnectionInfo {
+    fn query(conn: &mut SqliteConnection, database_url: &str) -> QueryResult<Self> {
+        let version = sql_query("SELECT sqlite_version() AS version")
+            .get_result::<SqliteVersion>(conn)?
+            .version;
+
+        Ok(SqliteConnectionInfo {
+            database: database_path(database_url).to_owned(),
+            version,
+        })
+    }
+
+    fn record_fields<'a>(&'a self, fields: &mut SpanFields<'a>) {
+        fields.push("db.name", self.database.as_str());
+        fields.push("db.version", self.version.as_str());
+    }
+}
+
+/// The path of the database file `database_url` opens, or `:memory:` for
+/// in memory databases.
+fn database_path(database_url: &str) -> &str {
+    let path = database_url.strip_prefix("file:").unwrap_or(database_url);
+    let path = path.split('?').next().unwrap_or(path);
+    if path.is_empty() {
+        ":memory:"
+    } else {
+        path
+    }
+}
+
+/// The fingerprint of `query`, rendered as `diesel::debug_query` renders it,
+/// if `TracingConfig::with_query_fingerprint` asks for it.
+fn fingerprint_of<T: QueryFragment<Sqlite>>(config: &TracingConfig, query: &T) -> Option<String> {
+    if !config.query_fingerprint {
+        return None;
+    }
+    guard::guarded("statement rendering", || {
+        fingerprint::fingerprint(&diesel::debug_query::<Sqlite, _>(query).to_string())
+    })
+}
+
+/// Creates the span for an operation on an established connection, with
+/// every optional field left empty until the operation has finished.
+macro_rules! connection_span {
+    ($level:expr, $name:literal $(, $($field:tt)+)?) => {
+        span_at!(
+            $level,
+            $name,
+            db.name = field::Empty,
+            db.system = "sqlite",
+            db.version = field::Empty,
+            otel.kind = "client",
+            correlation_id = field::Empty,
+            db.response.rows = field::Empty,
+            db.result = field::Empty,
+            db.query.fingerprint = field::Empty,
+            otel.name = field::Empty,
+            db.operation.name = field::Empty,
+            db.test_transaction = field::Empty,
+            db.transaction.depth = field::Empty,
+            db.transaction.status = field::Empty,
+            db.namespace = field::Empty,
+            otel.status_code = field::Empty,
+            otel.status_description = field::Empty,
+            $($($field)+)?
+        )
+    };
+}
 
 pub struct InstrumentedSqliteConnection {
     inner: SqliteConnection,
+    info: SqliteConnectionInfo,
+    config: TracingConfig,
+    span_sampler: SpanSampler,
+    // whether a test transaction has begun, which is never committed
+    test_transaction: bool,
+    #[cfg(feature = "registry")]
+    registration: crate::registry::Registration,
 }
 
 impl SimpleConnection for InstrumentedSqliteConnection {
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, query), err)]
-    fn batch_execute(&self, query: &str) -> QueryResult<()> {
-        self.inner.batch_execute(query)?;
-
-        Ok(())
+    fn batch_execute(&mut self, query: &str) -> QueryResult<()> {
+        let level = self.config.query_span_level;
+        let mut span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "batch_execute")
+        });
+        let fingerprint = self
+            .config
+            .query_fingerprint
+            .then(|| fingerprint::fingerprint(query));
+        self.in_span(&mut span, false, fingerprint, |conn| {
+            debug!("executing batch query");
+            conn.batch_execute(query)
+        })
     }
 }
 
@@ -25,90 +145,708 @@ impl Connection for InstrumentedSqliteConnection {
     type Backend = Sqlite;
     type TransactionManager = AnsiTransactionManager;
 
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(database_url), err)]
     fn establish(database_url: &str) -> ConnectionResult<InstrumentedSqliteConnection> {
-        Ok(InstrumentedSqliteConnection {
-            inner: SqliteConnection::establish(database_url)?,
-        })
+        InstrumentedSqliteConnection::establish_with_config(database_url, TracingConfig::global())
     }
 
     #[doc(hidden)]
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, query), err)]
-    fn execute(&self, query: &str) -> QueryResult<usize> {
-        self.inner.execute(query)
-    }
-
-    #[doc(hidden)]
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, source), err)]
-    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    fn execute_returning_count<T>(&mut self, source: &T) -> QueryResult<usize>
     where
-        T: AsQuery,
-        T::Query: QueryFragment<Sqlite> + QueryId,
-        Sqlite: HasSqlType<T::SqlType>,
-        U: Queryable<T::SqlType, Sqlite>,
+        T: QueryFragment<Sqlite> + QueryId,
     {
-        self.inner.query_by_index(source)
+        let level = self.config.query_span_level;
+        let mut span = self.span_sampler.span(&self.config, move || {
+            connection_span!(level, "execute_returning_count")
+        });
+        let fingerprint = fingerprint_of(&self.config, source);
+        self.in_span(&mut span, false, fingerprint, |conn| {
+            debug!("executing returning count");
+            conn.execute_returning_count(source)
+        })
     }
 
-    #[doc(hidden)]
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, source), err)]
-    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
     where
-        T: QueryFragment<Sqlite> + QueryId,
-        U: QueryableByName<Sqlite>,
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<Error>,
     {
-        self.inner.query_by_name(source)
+        let span = connection_span!(self.config.query_span_level, "transaction");
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        debug!("running transaction");
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = Self::TransactionManager::transaction(self, f);
+        if result.is_err() {
+            span.record("otel.status_code", "ERROR");
+        }
+        #[cfg(feature = "metrics")]
+        meter::transaction(
+            "sqlite",
+            Some(&self.info.database),
+            start.elapsed(),
+            if result.is_err() {
+                "rollback"
+            } else {
+                "commit"
+            },
+        );
+        result
+    }
+
+    fn begin_test_transaction(&mut self) -> QueryResult<()> {
+        let mut span = QuerySpan::from(connection_span!(
+            self.config.query_span_level,
+            "begin_test_transaction"
+        ));
+        self.test_transaction = true;
+        self.in_span(&mut span, false, None, |conn| {
+            debug!("beginning test transaction");
+            conn.begin_test_transaction()
+        })
     }
 
     #[doc(hidden)]
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, source), err)]
-    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    fn transaction_state(
+        &mut self,
+    ) -> &mut <Self::TransactionManager as TransactionManager<Self>>::TransactionStateData {
+        let span = connection_span!(self.config.query_span_level, "transaction_state");
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        debug!("retrieving transaction state");
+        self.inner.transaction_state()
+    }
+
+    fn instrumentation(&mut self) -> &mut dyn Instrumentation {
+        self.inner.instrumentation()
+    }
+
+    fn set_instrumentation(&mut self, instrumentation: impl Instrumentation) {
+        self.inner.set_instrumentation(instrumentation);
+    }
+
+    fn set_prepared_statement_cache_size(&mut self, size: CacheSize) {
+        let span = connection_span!(
+            self.config.query_span_level,
+            "set_prepared_statement_cache_size"
+        );
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        info!(
+            db.statement_cache.size = connection::cache_size_name(size),
+            "prepared statement cache size changed",
+        );
+        self.inner.set_prepared_statement_cache_size(size);
+    }
+}
+
+impl LoadConnection for InstrumentedSqliteConnection {
+    // the rows are collected to count them, which copies their values out of
+    // the statement as it is stepped
+    type Cursor<'conn, 'query> = std::vec::IntoIter<QueryResult<Self::Row<'conn, 'query>>>;
+    type Row<'conn, 'query> = <SqliteConnection as LoadConnection>::Row<'conn, 'query>;
+
+    fn load<'conn, 'query, T>(
+        &'conn mut self,
+        source: T,
+    ) -> QueryResult<Self::Cursor<'conn, 'query>>
     where
-        T: QueryFragment<Sqlite> + QueryId,
+        T: Query + QueryFragment<Sqlite> + QueryId + 'query,
+        Sqlite: QueryMetadata<T::SqlType>,
     {
-        self.inner.execute_returning_count(source)
+        let level = self.config.query_span_level;
+        let mut span = self
+            .span_sampler
+            .span(&self.config, move || connection_span!(level, "load"));
+        let fingerprint = fingerprint_of(&self.config, &source);
+        self.in_span(&mut span, true, fingerprint, |conn| {
+            debug!("loading rows");
+            Ok(conn.load(source)?.collect::<Vec<_>>())
+        })
+        .map(Vec::into_iter)
     }
+}
 
-    #[doc(hidden)]
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self))]
-    fn transaction_manager(&self) -> &Self::TransactionManager {
-        &self.inner.transaction_manager()
+// lets the connection be a variant of a `#[derive(MultiConnection)]` enum
+impl MultiConnectionHelper for InstrumentedSqliteConnection {
+    fn to_any<'a>(lookup: &mut <Sqlite as TypeMetadata>::MetadataLookup) -> &mut (dyn Any + 'a) {
+        <SqliteConnection as MultiConnectionHelper>::to_any(lookup)
+    }
+
+    fn from_any(lookup: &mut dyn Any) -> Option<&mut <Sqlite as TypeMetadata>::MetadataLookup> {
+        <SqliteConnection as MultiConnectionHelper>::from_any(lookup)
+    }
+}
+
+impl MigrationConnection for InstrumentedSqliteConnection {
+    fn setup(&mut self) -> QueryResult<usize> {
+        self.inner.setup()
+    }
+}
+
+impl R2D2Connection for InstrumentedSqliteConnection {
+    fn ping(&mut self) -> QueryResult<()> {
+        let mut span = QuerySpan::from(connection_span!(
+            self.config.query_span_level,
+            "ping",
+            db.duration_ms = field::Empty,
+        ));
+        let start = std::time::Instant::now();
+        let result = self.in_span(&mut span, false, None, |conn| {
+            debug!("pinging connection");
+            conn.ping()
+        });
+        span.record("db.duration_ms", start.elapsed().as_secs_f64() * 1000.0);
+        result
     }
 }
 
 impl InstrumentedSqliteConnection {
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, f))]
-    pub fn immediate_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    /// The instrumented connection boxed as `conn`, if it is one, for code
+    /// given connections as `Box<dyn BoxableConnection<Sqlite>>`.
+    ///
+    /// Like every connection, the instrumented connection is a
+    /// `BoxableConnection` of its backend, so it can be boxed as one in place
+    /// of the connection it wraps, and its operations are traced when run
+    /// through the box.
+    #[must_use]
+    pub fn downcast_ref(conn: &dyn BoxableConnection<Sqlite>) -> Option<&Self> {
+        conn.as_any().downcast_ref()
+    }
+
+    /// The wrapped connection, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced.
+    #[must_use]
+    pub fn as_inner(&self) -> &SqliteConnection {
+        &self.inner
+    }
+
+    /// The wrapped connection, mutably, for APIs specific to the backend.
+    ///
+    /// Operations run on it directly are not traced, and transactions should
+    /// not be started or ended through it as the wrapper would not know of
+    /// them.
+    pub fn as_inner_mut(&mut self) -> &mut SqliteConnection {
+        &mut self.inner
+    }
+
+    /// Unwraps the connection, which is no longer traced.
+    #[must_use]
+    pub fn into_inner(self) -> SqliteConnection {
+        self.inner
+    }
+
+    /// Establishes a connection, recording telemetry as described by `config`.
+    ///
+    /// Only the field budget, the fields mirrored into logs, the correlation
+    /// id, the span levels, the slow query threshold, query sampling, query
+    /// fingerprints and the size of the prepared statement cache apply to
+    /// these connections, the other options being specific to the
+    /// `postgres` backend.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the database cannot be opened.
+    pub fn establish_with_config(
+        database_url: &str,
+        config: TracingConfig,
+    ) -> ConnectionResult<InstrumentedSqliteConnection> {
+        let span = span_at!(
+            config.establish_span_level,
+            "establish_with_config",
+            db.name = field::Empty,
+            db.system = "sqlite",
+            db.version = field::Empty,
+            otel.kind = "client",
+            correlation_id = field::Empty,
+            db.namespace = field::Empty,
+        );
+        let _entered = span.enter();
+        let result = Self::establish_recorded(database_url, config);
+        if let Err(e) = &result {
+            error!(error = %e);
+        }
+        result
+    }
+
+    /// Establishes a connection, recording what is known about it on the
+    /// current span.
+    fn establish_recorded(
+        database_url: &str,
+        config: TracingConfig,
+    ) -> ConnectionResult<InstrumentedSqliteConnection> {
+        debug!("establishing sqlite connection");
+        let mut conn = SqliteConnection::establish(database_url)?;
+        if let Some(size) = config.statement_cache_size {
+            conn.set_prepared_statement_cache_size(size);
+        }
+
+        debug!("querying sqlite connection information");
+        let info = SqliteConnectionInfo::query(&mut conn, database_url)
+            .map_err(ConnectionError::CouldntSetupConfiguration)?;
+
+        #[cfg(feature = "registry")]
+        let registration =
+            crate::registry::Registration::new("sqlite", info.database.clone(), None);
+
+        let conn = InstrumentedSqliteConnection {
+            inner: conn,
+            info,
+            config,
+            span_sampler: SpanSampler::default(),
+            test_transaction: false,
+            #[cfg(feature = "registry")]
+            registration,
+        };
+        conn.record_fields(&Span::current(), SpanFields::default());
+
+        Ok(conn)
+    }
+
+    /// Runs `f` in a transaction started with `BEGIN IMMEDIATE`, which takes
+    /// the database's write lock straight away.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of `f`, or if the transaction cannot be started,
+    /// committed or rolled back.
+    pub fn immediate_transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
     where
-        F: FnOnce() -> Result<T, E>,
+        F: FnOnce(&mut Self) -> Result<T, E>,
         E: From<Error>,
     {
-        self.inner.immediate_transaction(f)
+        self.transaction_sql("BEGIN IMMEDIATE", f)
     }
 
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, f))]
-    pub fn exclusive_transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    /// Runs `f` in a transaction started with `BEGIN EXCLUSIVE`, which keeps
+    /// other connections from reading the database until it ends.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the error of `f`, or if the transaction cannot be started,
+    /// committed or rolled back.
+    pub fn exclusive_transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
     where
-        F: FnOnce() -> Result<T, E>,
+        F: FnOnce(&mut Self) -> Result<T, E>,
         E: From<Error>,
     {
-        self.inner.exclusive_transaction(f)
+        self.transaction_sql("BEGIN EXCLUSIVE", f)
     }
 
     #[doc(hidden)]
-    #[instrument(fields(db.system="sqlite", otel.kind="client"), skip(self, f))]
     pub fn register_sql_function<ArgsSqlType, RetSqlType, Args, Ret, F>(
-        &self,
+        &mut self,
         fn_name: &str,
         deterministic: bool,
         f: F,
     ) -> QueryResult<()>
     where
-        F: FnMut(Args) -> Ret + Send + 'static,
-        Args: Queryable<ArgsSqlType, Sqlite>,
+        F: FnMut(Args) -> Ret + std::panic::UnwindSafe + Send + 'static,
+        Args: FromSqlRow<ArgsSqlType, Sqlite> + StaticallySizedRow<ArgsSqlType, Sqlite>,
         Ret: ToSql<RetSqlType, Sqlite>,
         Sqlite: HasSqlType<RetSqlType>,
     {
         self.inner.register_sql_function(fn_name, deterministic, f)
     }
+
+    /// Runs `f` in a transaction started with `begin`, as diesel does for the
+    /// transactions specific to sqlite.
+    fn transaction_sql<T, E, F>(&mut self, begin: &'static str, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<Error>,
+    {
+        let span = connection_span!(self.config.query_span_level, "transaction");
+        self.record_fields(&span, SpanFields::default());
+        let _entered = span.enter();
+        debug!(begin, "running transaction");
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+
+        AnsiTransactionManager::begin_transaction_sql(self, begin)?;
+        let result = match f(self) {
+            Ok(value) => {
+                AnsiTransactionManager::commit_transaction(self)?;
+                Ok(value)
+            }
+            Err(e) => {
+                AnsiTransactionManager::rollback_transaction(self)?;
+                Err(e)
+            }
+        };
+        #[cfg(feature = "metrics")]
+        meter::transaction(
+            "sqlite",
+            Some(&self.info.database),
+            start.elapsed(),
+            if result.is_err() {
+                "rollback"
+            } else {
+                "commit"
+            },
+        );
+        result
+    }
+
+    /// Runs `f` against the inner connection inside `span`, recording the
+    /// connection fields, the fingerprint of the statement and the outcome
+    /// once it has returned.
+    fn in_span<R: RowCount>(
+        &mut self,
+        span: &mut QuerySpan<'_>,
+        read: bool,
+        fingerprint: Option<String>,
+        f: impl FnOnce(&mut SqliteConnection) -> QueryResult<R>,
+    ) -> QueryResult<R> {
+        let mut fields = SpanFields::default();
+        connection::record_transaction(&mut self.inner, false, &mut fields);
+
+        let entered = span.enter();
+        hooks::start(&self.config, "sqlite", &fields);
+        let start = std::time::Instant::now();
+        let result = traced::named(&mut fields, || f(&mut self.inner));
+        let elapsed = start.elapsed();
+        drop(entered);
+        let span = span.outcome(result.is_err());
+        let _entered = span.enter();
+        summary::record(elapsed, result.is_err());
+        #[cfg(feature = "metrics")]
+        meter::query(
+            "sqlite",
+            Some(&self.info.database),
+            span,
+            elapsed,
+            result.is_err(),
+        );
+        slow::check(&self.config, elapsed, None::<&str>);
+        #[cfg(feature = "registry")]
+        self.registration.record_query();
+
+        if let Some(fingerprint) = fingerprint {
+            fields.push("db.query.fingerprint", fingerprint);
+        }
+        if let Some(rows) = result.as_ref().ok().and_then(RowCount::row_count) {
+            fields.push("db.response.rows", rows);
+            if read {
+                fields.push("db.result", if rows == 0 { "empty" } else { "rows" });
+            }
+        }
+        let error = result.as_ref().err();
+        fields.push_status(error);
+        let fields = self.record_fields(span, fields);
+        fields.log(&self.config, error);
+        hooks::end(&self.config, "sqlite", &fields, elapsed, error);
+
+        result
+    }
+
+    /// Completes `fields` with the connection fields, records them on `span`
+    /// and returns them.
+    fn record_fields<'a>(&'a self, span: &Span, mut fields: SpanFields<'a>) -> SpanFields<'a> {
+        self.info.record_fields(&mut fields);
+        if let Some(id) = self.config.correlation_id() {
+            fields.push("correlation_id", id);
+        }
+        if self.test_transaction {
+            fields.push("db.test_transaction", true);
+        }
+        fields.record(span, &self.config);
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+
+    #[test]
+    fn test_database_path() {
+        assert_eq!(database_path(":memory:"), ":memory:");
+        assert_eq!(database_path("file:app.db?mode=ro"), "app.db");
+        assert_eq!(database_path("/var/lib/app.db"), "/var/lib/app.db");
+    }
+
+    #[test]
+    fn test_spans_record_database() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish(":memory:")
+            .expect("failed to establish connection");
+        conn.immediate_transaction::<_, Error, _>(|conn| {
+            conn.batch_execute("CREATE TABLE t (id INTEGER)")
+        })
+        .expect("failed to run transaction");
+
+        let span = capture.span("batch_execute");
+        assert_eq!(span.field("db.system"), Some("sqlite"));
+        assert_eq!(span.field("db.name"), Some(":memory:"));
+        assert!(span.field("db.version").is_some());
+        assert_eq!(
+            capture.span("transaction").field("db.name"),
+            Some(":memory:")
+        );
+    }
+
+    #[test]
+    fn test_ping_is_traced() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish(":memory:")
+            .expect("failed to establish connection");
+        conn.ping().expect("failed to ping");
+
+        let span = capture.span("ping");
+        assert_eq!(span.field("db.name"), Some(":memory:"));
+        assert!(span.field("db.duration_ms").is_some());
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_constants() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish_with_config(
+            ":memory:",
+            TracingConfig::default().with_query_fingerprint(true),
+        )
+        .expect("failed to establish connection");
+        sql_query("SELECT 1 WHERE 1 = 1")
+            .execute(&mut conn)
+            .expect("failed to execute");
+        sql_query("SELECT 2   WHERE 2 = 2")
+            .execute(&mut conn)
+            .expect("failed to execute");
+
+        let spans = capture.spans_named("execute_returning_count");
+        assert!(spans[0].field("db.query.fingerprint").is_some());
+        assert_eq!(
+            spans[0].field("db.query.fingerprint"),
+            spans[1].field("db.query.fingerprint")
+        );
+    }
+
+    #[test]
+    fn test_unsampled_queries_still_record_errors() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish_with_config(
+            ":memory:",
+            TracingConfig::default().with_query_sampling(2),
+        )
+        .expect("failed to establish connection");
+        conn.batch_execute("SELECT 1").expect("failed to execute");
+        conn.batch_execute("SELECT 2").expect("failed to execute");
+        conn.batch_execute("SELECT 3").expect("failed to execute");
+        assert_eq!(capture.spans_named("batch_execute").len(), 2);
+
+        assert!(conn.batch_execute("SELECT * FROM no_such_table").is_err());
+        let spans = capture.spans_named("batch_execute");
+        assert_eq!(spans.len(), 3);
+        assert!(spans[2].failed);
+        assert_eq!(spans[2].field("db.system"), Some("sqlite"));
+        let error = capture.error_event();
+        assert_eq!(error.field("error"), Some("no such table: no_such_table"));
+        assert_eq!(error.parent, Some("batch_execute"));
+    }
+
+    #[test]
+    fn test_spans_in_test_transactions_are_marked() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish(":memory:")
+            .expect("failed to establish connection");
+        conn.batch_execute("SELECT 1").expect("failed to execute");
+        assert_eq!(
+            capture.span("batch_execute").field("db.test_transaction"),
+            None
+        );
+
+        conn.begin_test_transaction()
+            .expect("failed to begin test transaction");
+        conn.batch_execute("SELECT 1").expect("failed to execute");
+        assert_eq!(
+            capture
+                .span("begin_test_transaction")
+                .field("db.test_transaction"),
+            Some("true")
+        );
+        assert_eq!(
+            capture.span("batch_execute").field("db.test_transaction"),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn test_statements_record_their_transaction() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish(":memory:")
+            .expect("failed to establish connection");
+        conn.batch_execute("SELECT 1").expect("failed to execute");
+        let span = capture.span("batch_execute");
+        assert_eq!(span.field("db.transaction.status"), Some("none"));
+        assert_eq!(span.field("db.transaction.depth"), None);
+
+        conn.transaction::<_, Error, _>(|conn| conn.batch_execute("SELECT 1"))
+            .expect("failed to run transaction");
+        let span = capture.span("batch_execute");
+        assert_eq!(span.field("db.transaction.status"), Some("active"));
+        assert_eq!(span.field("db.transaction.depth"), Some("1"));
+    }
+
+    #[test]
+    fn test_failed_transactions_record_their_status() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish(":memory:")
+            .expect("failed to establish connection");
+        conn.transaction::<_, Error, _>(|conn| conn.batch_execute("SELECT 1"))
+            .expect("failed to run transaction");
+        assert_eq!(capture.span("transaction").field("otel.status_code"), None);
+
+        conn.transaction::<(), _, _>(|_| Err(Error::RollbackTransaction))
+            .expect_err("the transaction was rolled back");
+        let span = capture.span("transaction");
+        assert_eq!(span.field("otel.status_code"), Some("ERROR"));
+        assert!(span.failed);
+    }
+
+    #[test]
+    fn test_traced_queries_name_their_spans() {
+        use crate::traced::TracedDsl;
+        use diesel::dsl::sql;
+        use diesel::sql_types::Integer;
+
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish(":memory:")
+            .expect("failed to establish connection");
+        let one: i32 = diesel::select(sql::<Integer>("1"))
+            .traced("load_one")
+            .get_result(&mut conn)
+            .expect("failed to load");
+        assert_eq!(one, 1);
+
+        let span = capture.span("load");
+        assert_eq!(span.field("otel.name"), Some("load_one"));
+        assert_eq!(span.field("db.operation.name"), Some("load_one"));
+
+        diesel::select(sql::<Integer>("1"))
+            .get_result::<i32>(&mut conn)
+            .expect("failed to load");
+        assert_eq!(capture.span("load").field("otel.name"), None);
+    }
+
+    #[test]
+    fn test_hooks_run_around_each_query() {
+        use std::sync::{Arc, Mutex};
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let (started, ended) = (Arc::clone(&calls), Arc::clone(&calls));
+        let config = TracingConfig::default()
+            .with_query_start_hook(move |query| {
+                started
+                    .lock()
+                    .unwrap()
+                    .push(format!("start {}", query.system));
+            })
+            .with_query_end_hook(move |query| {
+                ended.lock().unwrap().push(format!(
+                    "end {} {:?} {}",
+                    query.system,
+                    query.rows,
+                    query.error.is_some()
+                ));
+            });
+        let mut conn = InstrumentedSqliteConnection::establish_with_config(":memory:", config)
+            .expect("failed to establish connection");
+        calls.lock().unwrap().clear();
+
+        sql_query("SELECT 1")
+            .execute(&mut conn)
+            .expect("failed to execute");
+        sql_query("SELECT * FROM missing")
+            .execute(&mut conn)
+            .expect_err("the table is missing");
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "start sqlite",
+                "end sqlite Some(0) false",
+                "start sqlite",
+                "end sqlite None true",
+            ]
+        );
+    }
+
+    #[derive(diesel::MultiConnection)]
+    enum AnyConnection {
+        Sqlite(InstrumentedSqliteConnection),
+    }
+
+    #[test]
+    fn test_connection_is_a_multi_connection_variant() {
+        use diesel::sql_types::Integer;
+        use diesel::IntoSql;
+
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn =
+            AnyConnection::establish(":memory:").expect("failed to establish connection");
+        let one: i32 = diesel::select(1.into_sql::<Integer>())
+            .get_result(&mut conn)
+            .expect("failed to load");
+        assert_eq!(one, 1);
+        assert_eq!(capture.span("load").field("db.system"), Some("sqlite"));
+    }
+
+    #[test]
+    fn test_boxed_connections_are_traced_and_downcast() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut boxed: Box<dyn BoxableConnection<Sqlite>> = Box::new(
+            InstrumentedSqliteConnection::establish(":memory:")
+                .expect("failed to establish connection"),
+        );
+        boxed.batch_execute("SELECT 1").expect("failed to execute");
+        assert_eq!(
+            capture.span("batch_execute").field("db.system"),
+            Some("sqlite")
+        );
+        assert!(InstrumentedSqliteConnection::downcast_ref(&*boxed).is_some());
+
+        let plain: Box<dyn BoxableConnection<Sqlite>> = Box::new(
+            SqliteConnection::establish(":memory:").expect("failed to establish connection"),
+        );
+        assert!(InstrumentedSqliteConnection::downcast_ref(&*plain).is_none());
+    }
+
+    #[test]
+    fn test_statement_cache_size_is_configurable() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let mut conn = InstrumentedSqliteConnection::establish_with_config(
+            ":memory:",
+            TracingConfig::default().with_prepared_statement_cache_size(CacheSize::Disabled),
+        )
+        .expect("failed to establish connection");
+        conn.set_prepared_statement_cache_size(CacheSize::Unbounded);
+
+        let span = capture.span("set_prepared_statement_cache_size");
+        assert_eq!(span.field("db.system"), Some("sqlite"));
+        let event = capture.event("prepared statement cache size changed");
+        assert_eq!(event.field("db.statement_cache.size"), Some("unbounded"));
+    }
 }
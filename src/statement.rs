@@ -0,0 +1,336 @@
+use crate::fingerprint;
+
+/// Statement keywords recognised as the operation a statement performs.
+const OPERATIONS: &[&str] = &[
+    "SELECT",
+    "INSERT",
+    "UPDATE",
+    "DELETE",
+    "MERGE",
+    "COPY",
+    "CREATE",
+    "ALTER",
+    "DROP",
+    "TRUNCATE",
+    "BEGIN",
+    "COMMIT",
+    "ROLLBACK",
+    "SAVEPOINT",
+    "RELEASE",
+    "SET",
+    "SHOW",
+    "PREPARE",
+    "EXECUTE",
+    "DEALLOCATE",
+];
+
+/// Returns `sql` with any leading whitespace and comments removed.
+fn skip_comments(mut sql: &str) -> &str {
+    loop {
+        sql = sql.trim_start();
+        if let Some(rest) = sql.strip_prefix("--") {
+            sql = rest.find('\n').map_or("", |end| &rest[end..]);
+        } else if let Some(rest) = sql.strip_prefix("/*") {
+            sql = rest.find("*/").map_or("", |end| &rest[end + 2..]);
+        } else {
+            return sql;
+        }
+    }
+}
+
+/// Finds the first occurrence of `keyword` in `sql` as a whole word,
+/// ignoring case.
+fn find_keyword(sql: &str, keyword: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let upper = sql.to_ascii_uppercase();
+
+    upper
+        .match_indices(keyword)
+        .map(|(index, _)| index)
+        .find(|&index| {
+            let before = upper[..index].chars().next_back();
+            let after = upper[index + keyword.len()..].chars().next();
+            !before.map_or(false, is_word) && !after.map_or(false, is_word)
+        })
+}
+
+/// Finds the last occurrence of `keyword` in `sql` as a whole word, ignoring
+/// case.
+fn rfind_keyword(sql: &str, keyword: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let upper = sql.to_ascii_uppercase();
+
+    upper
+        .rmatch_indices(keyword)
+        .map(|(index, _)| index)
+        .find(|&index| {
+            let before = upper[..index].chars().next_back();
+            let after = upper[index + keyword.len()..].chars().next();
+            !before.map_or(false, is_word) && !after.map_or(false, is_word)
+        })
+}
+
+/// The operation performed by `sql`, taken from its first keyword.
+pub(crate) fn operation(sql: &str) -> Option<&'static str> {
+    let sql = skip_comments(sql);
+    let keyword = &sql[..sql
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or_else(|| sql.len())];
+
+    OPERATIONS
+        .iter()
+        .find(|operation| operation.eq_ignore_ascii_case(keyword))
+        .copied()
+}
+
+/// The name of the prepared statement managed by `sql`, if it is a
+/// `PREPARE`, `EXECUTE` or `DEALLOCATE` statement.
+pub(crate) fn prepared_name(sql: &str) -> Option<String> {
+    let mut words = skip_comments(sql)
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ';')
+        .filter(|word| !word.is_empty());
+    let keyword = words.next()?.to_ascii_uppercase();
+    let mut name = words.next()?;
+    match keyword.as_str() {
+        "PREPARE" | "EXECUTE" => {}
+        "DEALLOCATE" => {
+            // the PREPARE keyword is optional
+            if name.eq_ignore_ascii_case("PREPARE") {
+                name = words.next()?;
+            }
+        }
+        _ => return None,
+    }
+
+    Some(name.trim_matches('"').to_owned())
+}
+
+/// The table `sql` operates on, taken from the first `FROM`, `INTO` or
+/// `UPDATE` in it, without any schema or quotes.
+pub(crate) fn table(sql: &str) -> Option<String> {
+    let (index, keyword) = ["FROM", "INTO", "UPDATE"]
+        .iter()
+        .filter_map(|keyword| Some((find_keyword(sql, keyword)?, keyword)))
+        .min()?;
+    let name = sql[index + keyword.len()..]
+        .trim_start()
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ',' || c == ';')
+        .next()?;
+    let name = name.rsplit('.').next()?.trim_matches('"');
+
+    if name.is_empty() || name.starts_with('$') {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+/// The row limit of `sql`, as rendered by `diesel::debug_query`.
+///
+/// The outermost `LIMIT` is assumed to be the last one in the statement. Its
+/// value may be a literal or a bind parameter, which is looked up in the
+/// list of binds `debug_query` appends. `None` is returned for statements
+/// without a limit or whose limit cannot be worked out.
+pub(crate) fn limit(sql: &str) -> Option<usize> {
+    let (sql, binds) = match sql.rfind(" -- binds: ") {
+        Some(index) => (&sql[..index], Some(&sql[index + " -- binds: ".len()..])),
+        None => (sql, None),
+    };
+
+    let index = rfind_keyword(sql, "LIMIT")?;
+    let value = sql[index + "LIMIT".len()..].split_whitespace().next()?;
+
+    match value.strip_prefix('$') {
+        Some(position) => {
+            let position: usize = position.parse().ok()?;
+            let binds = binds?.trim().strip_prefix('[')?.strip_suffix(']')?;
+            binds
+                .split(", ")
+                .nth(position.checked_sub(1)?)?
+                .parse()
+                .ok()
+        }
+        None => value.parse().ok(),
+    }
+}
+
+/// The number of rows in the `VALUES` list of the `INSERT` statement `sql`,
+/// counted as the parenthesised lists at its top level.
+pub(crate) fn insert_rows(sql: &str) -> Option<usize> {
+    let index = find_keyword(sql, "VALUES")?;
+    let mut depth = 0_usize;
+    let mut rows = 0;
+    let mut quoted = false;
+    for c in sql[index + "VALUES".len()..].chars() {
+        match c {
+            '\'' => quoted = !quoted,
+            _ if quoted => {}
+            '(' => {
+                if depth == 0 {
+                    rows += 1;
+                }
+                depth += 1;
+            }
+            ')' => depth = depth.checked_sub(1)?,
+            // the list ends where anything but another row follows it
+            c if depth == 0 && c != ',' && !c.is_whitespace() => break,
+            _ => {}
+        }
+    }
+    (rows > 0).then_some(rows)
+}
+
+/// A low cardinality name for a span running an `operation` on `table` of
+/// `database`, as the semantic conventions name database spans.
+pub(crate) fn span_name(operation: &str, database: Option<&str>, table: Option<&str>) -> String {
+    match (database, table) {
+        (Some(database), Some(table)) => format!("{} {}.{}", operation, database, table),
+        (None, Some(name)) | (Some(name), None) => format!("{} {}", operation, name),
+        (None, None) => operation.to_owned(),
+    }
+}
+
+/// The command tag the server reports for `operation` affecting `rows` rows.
+///
+/// Diesel only exposes the row count, so the tag is rebuilt from it for the
+/// operations whose tags carry one.
+pub(crate) fn command_tag(operation: &str, rows: usize) -> Option<String> {
+    match operation {
+        // the 0 is where the OID of the inserted row used to be reported
+        "INSERT" => Some(format!("INSERT 0 {}", rows)),
+        "UPDATE" | "DELETE" | "MERGE" | "SELECT" | "COPY" => {
+            Some(format!("{} {}", operation, rows))
+        }
+        _ => None,
+    }
+}
+
+/// A coarse hint of how expensive `sql` is likely to be to run, from the
+/// number of joins and subqueries it has, with grouping and window functions
+/// counting double.
+///
+/// This is a heuristic for prioritising which queries to look at, not a
+/// measure of their cost.
+pub(crate) fn complexity(sql: &str) -> &'static str {
+    let words = fingerprint::words(sql);
+    let count = |word: &str| words.iter().filter(|w| *w == word).count();
+    let group_bys = words
+        .windows(2)
+        .filter(|pair| pair[0] == "group" && pair[1] == "by")
+        .count();
+    let subqueries = count("select").saturating_sub(1);
+
+    match count("join") + subqueries + 2 * (group_bys + count("over")) {
+        0 => "low",
+        1..=3 => "medium",
+        _ => "high",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_skips_comments() {
+        assert_eq!(
+            operation("  -- bump\n/* counters */ update t set n = n + 1"),
+            Some("UPDATE")
+        );
+        assert_eq!(operation("VACUUM"), None);
+    }
+
+    #[test]
+    fn test_span_name_from_known_parts() {
+        assert_eq!(
+            span_name("SELECT", Some("app"), Some("users")),
+            "SELECT app.users"
+        );
+        assert_eq!(span_name("DELETE", None, Some("users")), "DELETE users");
+        assert_eq!(span_name("SET", Some("app"), None), "SET app");
+        assert_eq!(span_name("BEGIN", None, None), "BEGIN");
+    }
+
+    #[test]
+    fn test_prepared_statement_name() {
+        assert_eq!(
+            prepared_name("PREPARE lookup (integer) AS SELECT $1"),
+            Some("lookup".to_owned())
+        );
+        assert_eq!(
+            prepared_name("execute lookup(1)"),
+            Some("lookup".to_owned())
+        );
+        assert_eq!(
+            prepared_name("DEALLOCATE PREPARE \"lookup\""),
+            Some("lookup".to_owned())
+        );
+        assert_eq!(prepared_name("DEALLOCATE ALL"), Some("ALL".to_owned()));
+        assert_eq!(prepared_name("SELECT 1"), None);
+    }
+
+    #[test]
+    fn test_limit_from_literal_or_bind() {
+        assert_eq!(limit("SELECT * FROM users LIMIT 20"), Some(20));
+        assert_eq!(
+            limit("SELECT id FROM users WHERE active = $1 LIMIT $2 -- binds: [true, 10]"),
+            Some(10)
+        );
+        assert_eq!(limit("SELECT * FROM unlimited"), None);
+    }
+
+    #[test]
+    fn test_insert_rows_counts_values_lists() {
+        assert_eq!(
+            insert_rows(r#"INSERT INTO "users" ("name") VALUES ($1), (DEFAULT) RETURNING "id""#),
+            Some(2)
+        );
+        assert_eq!(
+            insert_rows("INSERT INTO notes (body) VALUES ('(not a row)')"),
+            Some(1)
+        );
+        assert_eq!(insert_rows("INSERT INTO users DEFAULT VALUES"), None);
+        assert_eq!(insert_rows("SELECT 1"), None);
+    }
+
+    #[test]
+    fn test_complexity_tiers() {
+        assert_eq!(
+            complexity("SELECT id, name FROM users WHERE id = $1"),
+            "low"
+        );
+        assert_eq!(
+            complexity("SELECT count(*) FROM orders GROUP BY customer_id"),
+            "medium"
+        );
+        assert_eq!(
+            complexity(
+                "SELECT * FROM a JOIN b ON a.id = b.a_id JOIN c ON b.id = c.b_id \
+                 LEFT JOIN d ON c.id = d.c_id JOIN e ON d.id = e.d_id"
+            ),
+            "high"
+        );
+        // keywords in strings and comments are not counted
+        assert_eq!(
+            complexity("SELECT 'join join join join' /* group by */"),
+            "low"
+        );
+    }
+
+    #[test]
+    fn test_table_from_statement() {
+        assert_eq!(
+            table(r#"SELECT "users"."id" FROM "public"."users" WHERE "users"."id" = $1"#),
+            Some("users".to_owned())
+        );
+        assert_eq!(
+            table(r#"INSERT INTO "orders" ("id") VALUES ($1)"#),
+            Some("orders".to_owned())
+        );
+        assert_eq!(
+            table("update accounts set x = 1"),
+            Some("accounts".to_owned())
+        );
+        assert_eq!(table("SELECT 1"), None);
+    }
+}
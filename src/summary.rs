@@ -0,0 +1,129 @@
+/*!
+Aggregated statistics for the database activity within a scope.
+
+For request level dashboards a single event per request is often more useful
+than a span for every query. A [`SummaryGuard`] collects the queries made by
+instrumented connections on the current thread while it is alive and emits
+one `INFO` event on drop with the query count, error count, total time and
+the slowest query.
+
+```no_run
+# fn handle_request() {}
+let _summary = diesel_tracing::summary::SummaryGuard::new();
+handle_request();
+// the summary is emitted here, when `_summary` is dropped
+```
+*/
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Stats {
+    queries: u64,
+    errors: u64,
+    total: Duration,
+    slowest: Duration,
+}
+
+thread_local! {
+    static ACTIVE: RefCell<Vec<(u64, Stats)>> = RefCell::new(Vec::new());
+    static NEXT_ID: RefCell<u64> = RefCell::new(0);
+}
+
+/// Adds an operation to every summary active on the current thread.
+pub(crate) fn record(elapsed: Duration, failed: bool) {
+    ACTIVE.with(|active| {
+        for (_, stats) in active.borrow_mut().iter_mut() {
+            stats.queries += 1;
+            if failed {
+                stats.errors += 1;
+            }
+            stats.total += elapsed;
+            stats.slowest = stats.slowest.max(elapsed);
+        }
+    });
+}
+
+/// Collects database statistics until dropped, then emits them as a single
+/// summary event.
+///
+/// Summaries only see operations on the thread that created them, and nested
+/// summaries each include the operations of the scopes within them.
+#[must_use = "the summary is emitted as soon as the guard is dropped"]
+pub struct SummaryGuard {
+    id: u64,
+    // the statistics live in a thread local, so the guard cannot move
+    _not_send: PhantomData<*const ()>,
+}
+
+impl SummaryGuard {
+    pub fn new() -> Self {
+        let id = NEXT_ID.with(|next| {
+            let mut next = next.borrow_mut();
+            *next += 1;
+            *next
+        });
+        ACTIVE.with(|active| active.borrow_mut().push((id, Stats::default())));
+
+        SummaryGuard {
+            id,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for SummaryGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SummaryGuard {
+    fn drop(&mut self) {
+        let stats = ACTIVE.with(|active| {
+            let mut active = active.borrow_mut();
+            active
+                .iter()
+                .position(|(id, _)| *id == self.id)
+                .map(|index| active.remove(index).1)
+        });
+
+        if let Some(stats) = stats {
+            tracing::info!(
+                db.summary.queries = stats.queries,
+                db.summary.errors = stats.errors,
+                db.summary.duration_ms = stats.total.as_secs_f64() * 1000.0,
+                db.summary.slowest_ms = stats.slowest.as_secs_f64() * 1000.0,
+                "database activity summary",
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Capture;
+
+    #[test]
+    fn test_summary_aggregates_operations() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        {
+            let _summary = SummaryGuard::new();
+            record(Duration::from_millis(5), false);
+            record(Duration::from_millis(20), true);
+            record(Duration::from_millis(10), false);
+        }
+        record(Duration::from_millis(40), false);
+
+        let event = capture.event("database activity summary");
+        assert_eq!(event.field("db.summary.queries"), Some("3"));
+        assert_eq!(event.field("db.summary.errors"), Some("1"));
+        let millis = |field| event.field(field).unwrap().parse::<f64>().unwrap();
+        assert!((millis("db.summary.duration_ms") - 35.0).abs() < 1e-6);
+        assert!((millis("db.summary.slowest_ms") - 20.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,307 @@
+/*!
+Capturing the spans and events of instrumented connections in tests.
+
+A [`Capture`] installed as the default subscriber keeps every span and event
+created on the current thread, with all the fields recorded on them, so that
+tests can assert on what their database operations recorded:
+
+```no_run
+# use diesel::prelude::*;
+# use diesel_tracing::pg::InstrumentedPgConnection;
+use diesel_tracing::test::Capture;
+
+# fn main() -> Result<(), Box<dyn std::error::Error>> {
+let capture = Capture::default();
+let _guard = capture.install();
+
+let mut conn = InstrumentedPgConnection::establish("postgres://localhost/test")?;
+conn.batch_execute("SELECT 1")?;
+
+let span = capture.span("batch_execute");
+assert_eq!(span.field("db.system"), Some("postgresql"));
+assert!(!span.failed);
+# Ok(())
+# }
+```
+
+This module needs the `test-util` feature.
+*/
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::DefaultGuard;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A span created while a [`Capture`] was installed.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CapturedSpan {
+    pub name: &'static str,
+    pub target: &'static str,
+    pub level: tracing::Level,
+    /// The fields given a value, when the span was created or since.
+    pub fields: HashMap<&'static str, String>,
+    /// Names of the spans this one follows from.
+    pub follows_from: Vec<&'static str>,
+    /// Name of the span this one was created in.
+    pub parent: Option<&'static str>,
+    /// Whether an error was logged in the span or it recorded
+    /// `otel.status_code` as `ERROR`.
+    pub failed: bool,
+}
+
+impl CapturedSpan {
+    /// The value recorded for the field `name`, formatted as a string.
+    #[must_use]
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+/// An event logged while a [`Capture`] was installed.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CapturedEvent {
+    pub target: &'static str,
+    pub level: tracing::Level,
+    /// The fields of the event, including its `message`.
+    pub fields: HashMap<&'static str, String>,
+    /// Name of the span the event was logged in.
+    pub parent: Option<&'static str>,
+}
+
+impl CapturedEvent {
+    /// The value of the field `name`, formatted as a string.
+    #[must_use]
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+/// A layer that keeps every span and event created while it is installed,
+/// along with all the fields recorded on them, so tests can make assertions
+/// about them.
+///
+/// The methods looking up a span or an event panic when there is none, to
+/// fail the test making the assertion.
+#[derive(Clone, Default)]
+pub struct Capture {
+    spans: Arc<Mutex<Vec<(Id, CapturedSpan)>>>,
+    events: Arc<Mutex<Vec<CapturedEvent>>>,
+}
+
+impl Capture {
+    /// Installs a subscriber with this layer as the default of the current
+    /// thread, until the guard is dropped.
+    pub fn install(&self) -> DefaultGuard {
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(self.clone()))
+    }
+
+    /// Returns every span captured, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// If a thread panicked while capturing a span.
+    #[must_use]
+    pub fn spans(&self) -> Vec<CapturedSpan> {
+        self.spans
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, span)| span.clone())
+            .collect()
+    }
+
+    /// Returns the most recently created span called `name`.
+    ///
+    /// # Panics
+    ///
+    /// If no span called `name` was captured.
+    #[must_use]
+    pub fn span(&self, name: &str) -> CapturedSpan {
+        self.spans_named(name)
+            .pop()
+            .unwrap_or_else(|| panic!("no span named {} was captured", name))
+    }
+
+    /// Returns every span called `name`, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// If a thread panicked while capturing a span.
+    #[must_use]
+    pub fn spans_named(&self, name: &str) -> Vec<CapturedSpan> {
+        self.spans()
+            .into_iter()
+            .filter(|span| span.name == name)
+            .collect()
+    }
+
+    /// Returns every span created in a span called `parent`, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// If a thread panicked while capturing a span.
+    #[must_use]
+    pub fn children(&self, parent: &str) -> Vec<CapturedSpan> {
+        self.spans()
+            .into_iter()
+            .filter(|span| span.parent == Some(parent))
+            .collect()
+    }
+
+    /// Returns the most recent event with the message `message`.
+    ///
+    /// # Panics
+    ///
+    /// If no event with the message `message` was captured.
+    #[must_use]
+    pub fn event(&self, message: &str) -> CapturedEvent {
+        self.events_named(message)
+            .pop()
+            .unwrap_or_else(|| panic!("no event {:?} was captured", message))
+    }
+
+    /// Returns the most recent event logging an error.
+    ///
+    /// # Panics
+    ///
+    /// If no event logging an error was captured.
+    #[must_use]
+    pub fn error_event(&self) -> CapturedEvent {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|event| event.field("error").is_some())
+            .cloned()
+            .expect("no error event was captured")
+    }
+
+    /// Returns every event with the message `message`, oldest first.
+    ///
+    /// # Panics
+    ///
+    /// If a thread panicked while capturing an event.
+    #[must_use]
+    pub fn events_named(&self, message: &str) -> Vec<CapturedEvent> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.field("message") == Some(message))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<S> Layer<S> for Capture
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut span = CapturedSpan {
+            name: attrs.metadata().name(),
+            target: attrs.metadata().target(),
+            level: *attrs.metadata().level(),
+            fields: HashMap::new(),
+            follows_from: Vec::new(),
+            parent: ctx
+                .span(id)
+                .and_then(|span| span.parent())
+                .map(|parent| parent.name()),
+            failed: false,
+        };
+        attrs.record(&mut FieldVisitor(&mut span.fields));
+        span.failed = span.field("otel.status_code") == Some("ERROR");
+        self.spans.lock().unwrap().push((id.clone(), span));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+        let mut spans = self.spans.lock().unwrap();
+        if let Some((_, span)) = spans.iter_mut().rev().find(|(span_id, _)| span_id == id) {
+            values.record(&mut FieldVisitor(&mut span.fields));
+            span.failed |= span.field("otel.status_code") == Some("ERROR");
+        }
+    }
+
+    fn on_follows_from(&self, id: &Id, follows: &Id, _ctx: Context<'_, S>) {
+        let mut spans = self.spans.lock().unwrap();
+        let follows = spans
+            .iter()
+            .rev()
+            .find(|(span_id, _)| span_id == follows)
+            .map(|(_, span)| span.name);
+        if let (Some(follows), Some((_, span))) = (
+            follows,
+            spans.iter_mut().rev().find(|(span_id, _)| span_id == id),
+        ) {
+            span.follows_from.push(follows);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut captured = CapturedEvent {
+            target: event.metadata().target(),
+            level: *event.metadata().level(),
+            fields: HashMap::new(),
+            parent: ctx.event_span(event).map(|span| span.name()),
+        };
+        event.record(&mut FieldVisitor(&mut captured.fields));
+        if let (Some(parent), true) = (ctx.event_span(event), captured.field("error").is_some()) {
+            let mut spans = self.spans.lock().unwrap();
+            if let Some((_, span)) = spans
+                .iter_mut()
+                .rev()
+                .find(|(span_id, _)| *span_id == parent.id())
+            {
+                span.failed = true;
+            }
+        }
+        self.events.lock().unwrap().push(captured);
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut HashMap<&'static str, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::error;
+
+    #[test]
+    fn test_spans_failing_and_children_are_captured() {
+        let capture = Capture::default();
+        let _guard = capture.install();
+
+        let outer = tracing::info_span!("outer");
+        outer.in_scope(|| {
+            tracing::info_span!("inner").in_scope(|| error!(error = "boom"));
+            let _ok = tracing::info_span!("sibling", db.name = "test");
+        });
+
+        let children = capture.children("outer");
+        assert_eq!(
+            children.iter().map(|span| span.name).collect::<Vec<_>>(),
+            ["inner", "sibling"]
+        );
+        assert!(capture.span("inner").failed);
+        assert!(!capture.span("outer").failed);
+        assert_eq!(capture.span("sibling").field("db.name"), Some("test"));
+    }
+}
@@ -0,0 +1,93 @@
+/*!
+Names for the spans of individual queries.
+
+The spans of typed queries are all called `load` or `execute_returning_count`,
+named for dashboards by the operation and table the query is found to run on
+when it renders. [`TracedDsl::traced`] gives a query a name of its own, which
+the span of the query records as `otel.name`, the name `OpenTelemetry`
+exporters give the span, and as `db.operation.name`:
+
+```no_run
+# #[cfg(feature = "postgres")]
+# fn run(conn: &mut diesel_tracing::pg::InstrumentedPgConnection) -> diesel::QueryResult<()> {
+use diesel::dsl::sql;
+use diesel::sql_types::Integer;
+use diesel::RunQueryDsl;
+use diesel_tracing::traced::TracedDsl;
+
+let answer: i32 = diesel::select(sql::<Integer>("42"))
+    .traced("load_answer")
+    .get_result(conn)?;
+# Ok(())
+# }
+```
+
+The name is picked up while the connection builds the statement of the query,
+so it is recorded on the spans of the synchronous connections of this crate,
+which build it on the thread running the query.
+*/
+use crate::fields::SpanFields;
+use diesel::backend::Backend;
+use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+use diesel::query_dsl::RunQueryDsl;
+use diesel::result::QueryResult;
+use std::cell::Cell;
+
+thread_local! {
+    static NAME: Cell<Option<&'static str>> = Cell::new(None);
+}
+
+/// A query named for the span it runs in, made by [`TracedDsl::traced`].
+///
+/// The query is otherwise run as it would be without the name, and shares its
+/// prepared statements.
+#[derive(Clone, Copy, Debug)]
+pub struct Traced<T> {
+    query: T,
+    name: &'static str,
+}
+
+/// Names queries for the spans they run in.
+pub trait TracedDsl: Sized {
+    /// Names the span of this query `name`, such as `load_active_users`.
+    fn traced(self, name: &'static str) -> Traced<Self> {
+        Traced { query: self, name }
+    }
+}
+
+impl<T> TracedDsl for T {}
+
+impl<T: QueryId> QueryId for Traced<T> {
+    type QueryId = T::QueryId;
+
+    const HAS_STATIC_QUERY_ID: bool = T::HAS_STATIC_QUERY_ID;
+}
+
+impl<T: Query> Query for Traced<T> {
+    type SqlType = T::SqlType;
+}
+
+impl<T, DB> QueryFragment<DB> for Traced<T>
+where
+    T: QueryFragment<DB>,
+    DB: Backend,
+{
+    fn walk_ast<'b>(&'b self, pass: AstPass<'_, 'b, DB>) -> QueryResult<()> {
+        NAME.with(|name| name.set(Some(self.name)));
+        self.query.walk_ast(pass)
+    }
+}
+
+impl<T, Conn> RunQueryDsl<Conn> for Traced<T> {}
+
+/// Runs `f`, a query run on the current thread, adding the name it was given
+/// with [`TracedDsl::traced`], if any, to `fields`.
+pub(crate) fn named<R>(fields: &mut SpanFields<'_>, f: impl FnOnce() -> R) -> R {
+    NAME.with(|name| name.set(None));
+    let result = f();
+    if let Some(name) = NAME.with(Cell::take) {
+        fields.push("otel.name", name);
+        fields.push("db.operation.name", name);
+    }
+    result
+}